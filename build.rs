@@ -0,0 +1,521 @@
+//! Generates the board configuration - matrix pin setup, translation table,
+//! indicator LEDs - from the layout file of the board selected with
+//! the board-* cargo features. See layouts/kinesis_advantage.txt for the format.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// cargo feature (without the board- prefix) and layout file of each board
+const BOARDS: &[(&str, &str)] = &[
+    ("kinesis-advantage", "layouts/kinesis_advantage.txt"),
+    ("bluepill-test", "layouts/bluepill_test.txt"),
+    ("planck", "layouts/planck.txt"),
+    ("ergodox", "layouts/ergodox.txt"),
+];
+
+const HOST_LEDS: &[&str] = &["NumLock", "CapsLock", "ScrollLock", "Compose", "Kana"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Pin {
+    port: char,
+    number: u8,
+}
+
+impl Pin {
+    fn parse(s: &str) -> Result<Pin, String> {
+        let port = match &s[..2.min(s.len())] {
+            "PA" => 'a',
+            "PB" => 'b',
+            _ => return Err(format!("pin {}: only PA and PB pins are supported", s)),
+        };
+        let number = s[2..]
+            .parse::<u8>()
+            .ok()
+            .filter(|n| *n < 16)
+            .ok_or_else(|| format!("pin {}: invalid pin number", s))?;
+        if port == 'a' && (number == 11 || number == 12) {
+            return Err(format!("pin {}: PA11/PA12 are used for USB", s));
+        }
+        if port == 'a' && (number == 9 || number == 10) {
+            return Err(format!("pin {}: PA9/PA10 are used for the serial console", s));
+        }
+        Ok(Pin { port, number })
+    }
+
+    /// the gpio control register for this pin
+    fn control_register(&self) -> &'static str {
+        if self.number < 8 {
+            "crl"
+        } else {
+            "crh"
+        }
+    }
+
+    /// a matrix input, pulled to the inactive level
+    fn input(&self, active_high: bool) -> String {
+        format!(
+            "gpio{port}.p{port}{n}.into_pull_{pull}_input(&mut gpio{port}.{cr}).downgrade()",
+            port = self.port,
+            n = self.number,
+            pull = if active_high { "down" } else { "up" },
+            cr = self.control_register()
+        )
+    }
+
+    /// a matrix output, starting at the inactive level
+    fn output(&self, active_high: bool) -> String {
+        let (mode, state) = if active_high {
+            ("push_pull", "Low")
+        } else {
+            ("open_drain", "High")
+        };
+        format!(
+            "gpio{port}.p{port}{n}.into_{mode}_output_with_state(&mut gpio{port}.{cr}, \
+             stm32f1xx_hal::gpio::State::{state}).downgrade()",
+            port = self.port,
+            n = self.number,
+            mode = mode,
+            cr = self.control_register(),
+            state = state
+        )
+    }
+
+    fn push_pull_output(&self) -> String {
+        format!(
+            "gpio{port}.p{port}{n}.into_push_pull_output(&mut gpio{port}.{cr}).downgrade()",
+            port = self.port,
+            n = self.number,
+            cr = self.control_register()
+        )
+    }
+}
+
+struct Key {
+    source: usize,
+    sink: usize,
+    keycode: String,
+    label: String,
+}
+
+/// a debounce_key entry
+struct DebounceKey {
+    source: usize,
+    sink: usize,
+    press: u8,
+    release: u8,
+}
+
+struct Layout {
+    name: String,
+    /// debouncer type, default thresholds
+    debounce: (&'static str, u8, u8),
+    debounce_keys: Vec<DebounceKey>,
+    ghost_detection: bool,
+    scan_rate: u32,
+    /// diodes point from the sources to the sinks (anode to cathode)
+    source_to_sink: bool,
+    active_high: bool,
+    sinks: Vec<Pin>,
+    sources: Vec<Pin>,
+    leds: Vec<(String, Pin)>,
+    keys: Vec<Key>,
+}
+
+fn parse_pins(fields: &[&str]) -> Result<Vec<Pin>, String> {
+    let pins = fields
+        .iter()
+        .map(|f| Pin::parse(f))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Matrix scans all PA pins first, the numbering has to match
+    if pins.windows(2).any(|w| w[0].port == 'b' && w[1].port == 'a') {
+        return Err("PA pins have to be listed before PB pins".to_string());
+    }
+    Ok(pins)
+}
+
+/// press and release thresholds, in scans
+fn parse_thresholds(fields: &[&str]) -> Result<(u8, u8), String> {
+    let number = |s: &str| {
+        s.parse::<u8>()
+            .map_err(|_| format!("invalid debounce threshold {} (0..255 scans)", s))
+    };
+    Ok((number(fields[0])?, number(fields[1])?))
+}
+
+/// the default strategy, which has no thresholds
+const LEGACY_DEBOUNCER: &str = "crate::debounce::LegacyDebouncer<KEY_COUNT>";
+
+fn parse(text: &str) -> Result<Layout, String> {
+    let mut name = None;
+    let mut ghost_detection = false;
+    let mut scan_rate = 100;
+    let mut debounce = (LEGACY_DEBOUNCER, 0, 0);
+    let mut debounce_keys = Vec::new();
+    let mut source_to_sink = false;
+    let mut active_high = false;
+    let mut sinks = Vec::new();
+    let mut sources = Vec::new();
+    let mut leds = Vec::new();
+    let mut keys = Vec::new();
+    for (ii, line) in text.lines().enumerate() {
+        let line_no = ii + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let err = |e: String| format!("line {}: {}", line_no, e);
+        match fields[0] {
+            "name" => name = Some(fields[1..].join(" ")),
+            "ghost_detection" => {
+                ghost_detection = match fields.get(1) {
+                    Some(&"yes") => true,
+                    Some(&"no") => false,
+                    _ => return Err(err("expected ghost_detection yes|no".into())),
+                }
+            }
+            // at most one complete scan per ms, the ms clock (TIM4, and the
+            // simulator's time step) couldn't tell more apart
+            "scan_rate" => {
+                scan_rate = match fields.get(1).and_then(|f| f.parse::<u32>().ok()) {
+                    Some(hz) if (1..=1000).contains(&hz) => hz,
+                    _ => return Err(err("expected scan_rate <1..1000 Hz>".into())),
+                }
+            }
+            "debounce" => {
+                let strategy = match fields.get(1) {
+                    Some(&"legacy") => LEGACY_DEBOUNCER,
+                    Some(&"deferred") => "crate::debounce::DeferredDebouncer<KEY_COUNT>",
+                    Some(&"eager") => "crate::debounce::EagerDebouncer<KEY_COUNT>",
+                    Some(&"counter") => "crate::debounce::CounterDebouncer<KEY_COUNT>",
+                    _ => return Err(err("expected debounce legacy|deferred|eager|counter".into())),
+                };
+                let (press, release) = match (fields.len(), strategy) {
+                    (2, LEGACY_DEBOUNCER) => (0, 0),
+                    (4, _) => parse_thresholds(&fields[2..]).map_err(err)?,
+                    _ => return Err(err("expected debounce <strategy> <press> <release>".into())),
+                };
+                debounce = (strategy, press, release);
+            }
+            "debounce_key" => {
+                if fields.len() != 5 {
+                    return Err(err(
+                        "expected debounce_key <source> <sink> <press> <release>".into(),
+                    ));
+                }
+                let number = |s: &str| {
+                    s.parse::<usize>()
+                        .map_err(|_| format!("line {}: invalid number {}", line_no, s))
+                };
+                let (press, release) = parse_thresholds(&fields[3..]).map_err(err)?;
+                debounce_keys.push(DebounceKey {
+                    source: number(fields[1])?,
+                    sink: number(fields[2])?,
+                    press,
+                    release,
+                });
+            }
+            "diodes" => {
+                source_to_sink = match fields.get(1) {
+                    Some(&"sink2source") => false,
+                    Some(&"source2sink") => true,
+                    _ => return Err(err("expected diodes sink2source|source2sink".into())),
+                }
+            }
+            "active" => {
+                active_high = match fields.get(1) {
+                    Some(&"low") => false,
+                    Some(&"high") => true,
+                    _ => return Err(err("expected active low|high".into())),
+                }
+            }
+            "led" => {
+                if fields.len() != 3 || !HOST_LEDS.contains(&fields[1]) {
+                    return Err(err(format!("expected led <{}> <pin>", HOST_LEDS.join("|"))));
+                }
+                leds.push((fields[1].to_string(), Pin::parse(fields[2]).map_err(err)?));
+            }
+            "sinks" => sinks = parse_pins(&fields[1..]).map_err(err)?,
+            "sources" => sources = parse_pins(&fields[1..]).map_err(err)?,
+            "key" => {
+                if fields.len() < 4 {
+                    return Err(err("expected key <source> <sink> <keycode> <label>".into()));
+                }
+                let number = |s: &str| {
+                    s.parse::<usize>()
+                        .map_err(|_| format!("line {}: invalid number {}", line_no, s))
+                };
+                keys.push(Key {
+                    source: number(fields[1])?,
+                    sink: number(fields[2])?,
+                    keycode: fields[3].to_string(),
+                    label: fields[4..].join(" "),
+                });
+            }
+            other => return Err(err(format!("unknown entry {}", other))),
+        }
+    }
+    Ok(Layout {
+        name: name.ok_or_else(|| "missing name entry".to_string())?,
+        ghost_detection,
+        scan_rate,
+        debounce,
+        debounce_keys,
+        source_to_sink,
+        active_high,
+        sinks,
+        sources,
+        leds,
+        keys,
+    })
+}
+
+/// returns the keycode expressions, in matrix index order
+fn validate(layout: &Layout) -> Result<Vec<(String, String)>, String> {
+    let mut errors = Vec::new();
+    let mut seen = HashMap::new();
+    let led_pins = layout.leds.iter().map(|(_led, pin)| pin);
+    for pin in layout.sinks.iter().chain(layout.sources.iter()).chain(led_pins) {
+        if seen.insert(*pin, ()).is_some() {
+            errors.push(format!(
+                "pin P{}{} is used twice",
+                pin.port.to_ascii_uppercase(),
+                pin.number
+            ));
+        }
+    }
+
+    let sink_count = layout.sinks.len();
+    let source_count = layout.sources.len();
+    if layout.ghost_detection && (sink_count > 32 || source_count > 32) {
+        errors.push("ghost_detection supports at most 32 sources and 32 sinks".to_string());
+    }
+    let mut table: Vec<Option<(String, String)>> = vec![None; sink_count * source_count];
+    for key in layout.keys.iter() {
+        if key.source >= source_count || key.sink >= sink_count {
+            errors.push(format!(
+                "key {} {} ({}) is outside the {}x{} matrix",
+                key.source, key.sink, key.label, source_count, sink_count
+            ));
+            continue;
+        }
+        let index = key.source * sink_count + key.sink;
+        let keycode = match key.keycode.as_str() {
+            // unused positions report their matrix index, like before
+            "unused" => format!("{}", index),
+            kc if kc.starts_with(|c: char| c.is_ascii_digit()) => kc.to_string(),
+            kc => format!("{}.to_u32()", kc),
+        };
+        if table[index].is_some() {
+            errors.push(format!("key {} {} is listed twice", key.source, key.sink));
+        }
+        table[index] = Some((keycode, key.label.clone()));
+    }
+    for key in layout.debounce_keys.iter() {
+        if key.source >= source_count || key.sink >= sink_count {
+            errors.push(format!(
+                "debounce_key {} {} is outside the {}x{} matrix",
+                key.source, key.sink, source_count, sink_count
+            ));
+        }
+    }
+    if !layout.debounce_keys.is_empty() && layout.debounce.0 == LEGACY_DEBOUNCER {
+        errors.push("debounce_key needs the deferred, eager or counter debounce strategy".to_string());
+    }
+    for (index, entry) in table.iter().enumerate() {
+        if entry.is_none() {
+            errors.push(format!(
+                "key {} {} is not mapped",
+                index / sink_count,
+                index % sink_count
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(table.into_iter().map(|e| e.unwrap()).collect())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+fn translation_source(table: &[(String, String)]) -> String {
+    let mut out = String::from("{\n    use keytokey::KeyCode::*;\n    &[\n");
+    for (index, (keycode, label)) in table.iter().enumerate() {
+        out.push_str(&format!("        {}, // {:#x} {}\n", keycode, index, label));
+    }
+    out.push_str("    ]\n}\n");
+    out
+}
+
+impl Layout {
+    /// the same rule as MatrixConfig::drives_sinks - current has to
+    /// flow through the diodes from the driven line (active high)
+    /// or into it (active low)
+    fn drives_sinks(&self) -> bool {
+        self.source_to_sink != self.active_high
+    }
+
+    /// (input pins, output pins)
+    fn inputs_outputs(&self) -> (&[Pin], &[Pin]) {
+        if self.drives_sinks() {
+            (&self.sources, &self.sinks)
+        } else {
+            (&self.sinks, &self.sources)
+        }
+    }
+}
+
+fn matrix_source(layout: &Layout) -> String {
+    let active_high = layout.active_high;
+    // PA pins first, then PB - the order the lines are numbered in
+    let list = |pins: &[Pin], output: bool| {
+        let entries: Vec<String> = pins
+            .iter()
+            .filter(|p| p.port == 'a')
+            .chain(pins.iter().filter(|p| p.port == 'b'))
+            .map(|p| {
+                let pin = if output { p.output(active_high) } else { p.input(active_high) };
+                format!("        crate::matrix::PortPin::p{}({}, {}),\n", p.port, pin, p.number)
+            })
+            .collect();
+        format!("    [\n{}    ],\n", entries.concat())
+    };
+    let (inputs, outputs) = layout.inputs_outputs();
+    format!(
+        "crate::matrix::Matrix::new(\n{}{}    crate::board::MATRIX_CONFIG,\n)\n",
+        list(inputs, false),
+        list(outputs, true),
+    )
+}
+
+fn matrix_type_source(layout: &Layout) -> String {
+    let (input, output) = if layout.active_high {
+        ("PullDown", "PushPull")
+    } else {
+        ("PullUp", "OpenDrain")
+    };
+    let (inputs, outputs) = layout.inputs_outputs();
+    let leds_on = |port: char| layout.leds.iter().filter(|(_led, pin)| pin.port == port).count();
+    format!(
+        "type BoardMatrix = crate::matrix::Matrix<\n    \
+         stm32f1xx_hal::gpio::{},\n    \
+         stm32f1xx_hal::gpio::{},\n    \
+         {},\n    \
+         {},\n    \
+         {{ crate::board::KEY_COUNT }},\n\
+         >;\n\
+         type BoardLeds = crate::leds::Leds<{}, {}>;\n",
+        input,
+        output,
+        inputs.len(),
+        outputs.len(),
+        leds_on('a'),
+        leds_on('b')
+    )
+}
+
+fn leds_source(layout: &Layout) -> String {
+    let list = |port: char| {
+        let entries: Vec<String> = layout
+            .leds
+            .iter()
+            .filter(|(_led, pin)| pin.port == port)
+            .map(|(led, pin)| format!("        (crate::keyboard::HostLed::{}, {}),\n", led, pin.push_pull_output()))
+            .collect();
+        format!("    [\n{}    ],\n", entries.concat())
+    };
+    format!("Leds::new(\n{}{})\n", list('a'), list('b'))
+}
+
+fn thresholds(press: u8, release: u8) -> String {
+    format!(
+        "crate::debounce::Thresholds {{ press: {}, release: {} }}",
+        press, release
+    )
+}
+
+fn board_source(layout: &Layout, path: &str) -> String {
+    let sink_count = layout.sinks.len();
+    let debounce_keys: String = layout
+        .debounce_keys
+        .iter()
+        .map(|key| {
+            format!(
+                "    ({}, {}),\n",
+                key.source * sink_count + key.sink,
+                thresholds(key.press, key.release)
+            )
+        })
+        .collect();
+    format!(
+        "// generated by build.rs from {}\n\
+         pub const NAME: &str = {:?};\n\
+         pub const SOURCE_COUNT: usize = {};\n\
+         pub const SINK_COUNT: usize = {};\n\
+         pub const KEY_COUNT: usize = SOURCE_COUNT * SINK_COUNT;\n\
+         pub const SCAN_RATE_HZ: u32 = {};\n\
+         #[cfg_attr(not(target_os = \"none\"), allow(dead_code))]\n\
+         pub const MATRIX_CONFIG: crate::key_matrix::MatrixConfig = \
+         crate::key_matrix::MatrixConfig {{\n    \
+         ghost_detection: {},\n    \
+         diodes: crate::key_matrix::DiodeDirection::{},\n    \
+         active: crate::key_matrix::ActiveLevel::{},\n}};\n\
+         pub type Debouncer = {};\n\
+         pub const DEBOUNCE: crate::debounce::Thresholds = {};\n\
+         pub const DEBOUNCE_KEYS: &[(usize, crate::debounce::Thresholds)] = &[\n{}];\n",
+        path,
+        layout.name,
+        layout.sources.len(),
+        layout.sinks.len(),
+        layout.scan_rate,
+        layout.ghost_detection,
+        if layout.source_to_sink { "SourceToSink" } else { "SinkToSource" },
+        if layout.active_high { "High" } else { "Low" },
+        layout.debounce.0,
+        thresholds(layout.debounce.1, layout.debounce.2),
+        debounce_keys
+    )
+}
+
+fn selected_board() -> &'static str {
+    let selected: Vec<&(&str, &str)> = BOARDS
+        .iter()
+        .filter(|(feature, _path)| {
+            let var = format!("CARGO_FEATURE_BOARD_{}", feature.to_uppercase().replace('-', "_"));
+            env::var_os(var).is_some()
+        })
+        .collect();
+    let features: Vec<String> = BOARDS.iter().map(|(f, _)| format!("board-{}", f)).collect();
+    match selected.as_slice() {
+        [(_feature, path)] => path,
+        [] => panic!("no board selected, enable one of {}", features.join(", ")),
+        _ => panic!(
+            "more than one board selected - use --no-default-features \
+             with exactly one of {}",
+            features.join(", ")
+        ),
+    }
+}
+
+/// generate the board files into OUT_DIR, `root` is the directory containing layouts/
+pub fn generate(root: &Path) {
+    let path = selected_board();
+    let file = root.join(path);
+    println!("cargo:rerun-if-changed={}", file.display());
+    let text =
+        fs::read_to_string(&file).unwrap_or_else(|e| panic!("could not read {}: {}", path, e));
+    let layout = parse(&text).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    let table = validate(&layout).unwrap_or_else(|e| panic!("{}:\n{}", path, e));
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out.join("translation.rs"), translation_source(&table)).unwrap();
+    fs::write(out.join("matrix.rs"), matrix_source(&layout)).unwrap();
+    fs::write(out.join("matrix_type.rs"), matrix_type_source(&layout)).unwrap();
+    fs::write(out.join("leds.rs"), leds_source(&layout)).unwrap();
+    fs::write(out.join("board.rs"), board_source(&layout, path)).unwrap();
+}
+
+fn main() {
+    generate(Path::new("."));
+}
@@ -1,6 +1,9 @@
 /* Linker script for the STM32F103C8T6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* the top 4K (0x0800F000..0x08010000) hold the keymap store, see keymap_store.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 60K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
+/* .uninit (bootloader flag, OOM record) comes from cortex-m-rt's link.x,
+   NOLOAD in RAM between .bss and the heap - see BUILDING.md */
@@ -0,0 +1,8 @@
+//! Generates the same board files as the firmware's build.rs
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod firmware_build;
+
+fn main() {
+    firmware_build::generate(std::path::Path::new(".."));
+}
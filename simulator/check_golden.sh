@@ -0,0 +1,7 @@
+#!/bin/bash
+# replays every script in scripts/ and compares the reports with its .golden file.
+# pass --bless to rewrite the golden files after an intended behaviour change.
+
+cd "$(dirname "$0")" &&\
+ cargo run --quiet --target "$(rustc -vV | sed -n 's/^host: //p')" --\
+  "${1:---check}" scripts/*.txt
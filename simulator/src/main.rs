@@ -0,0 +1,345 @@
+//! Runs the firmware's key pipeline on the host.
+//!
+//! A script of timestamped matrix presses/releases is played back
+//! through the debouncer and get_keytokey, at the firmware's scan rate,
+//! and every HID report that would go to the host is printed -
+//! built by the firmware's USBOut, writing to RecordingSinks.
+//! usage: k2k_simulator [--check | --bless] <script>...
+//!
+//! Script lines are `<time ms> press|release <matrix index>`, # starts a comment.
+//!
+//! With --check the output is compared against the script's .golden file
+//! (scripts/numpad.txt -> scripts/numpad.golden), --bless rewrites the golden files.
+//! check_golden.sh runs all scripts that way, and so does cargo test.
+
+#[path = "../../src/board.rs"]
+mod board;
+#[path = "../../src/debounce.rs"]
+#[allow(dead_code)]
+mod debounce;
+#[path = "../../src/extended_handlers.rs"]
+mod extended_handlers;
+#[path = "../../src/extra_keys.rs"]
+#[allow(dead_code)]
+mod extra_keys;
+#[path = "../../src/hid.rs"]
+#[allow(dead_code)]
+mod hid;
+#[path = "../../src/key_matrix.rs"]
+#[allow(dead_code)]
+mod key_matrix;
+#[path = "../../src/keyboard.rs"]
+#[allow(dead_code)]
+mod keyboard;
+#[path = "../../src/keymap.rs"]
+mod keymap;
+#[path = "../../src/latency.rs"]
+#[allow(dead_code)]
+mod latency;
+#[path = "../../src/mouse.rs"]
+#[allow(dead_code)]
+mod mouse;
+#[path = "../../src/report_sink.rs"]
+#[allow(dead_code)]
+mod report_sink;
+#[path = "../../src/ring_buffer.rs"]
+#[allow(dead_code)]
+mod ring_buffer;
+#[path = "../../src/usbout.rs"]
+mod usbout;
+
+use crate::debounce::Debounce;
+use crate::hid::{ExtraReport, MouseReport};
+use crate::key_matrix::{process_scan, KeyMatrix, ScriptedMatrix};
+use crate::keymap::{get_keytokey, TRANSLATION};
+use crate::report_sink::RecordingSink;
+use crate::usbout::{StringSender, USBOut};
+use std::path::Path;
+use std::{env, fs, process};
+
+/// keep running this long after the last event, so timeouts fire
+const RUN_OUT_MS: u32 = 1000;
+
+/// debug output, with the time it was written
+struct DebugLog {
+    time_ms: u32,
+    lines: Vec<(u32, String)>,
+}
+
+impl StringSender for DebugLog {
+    fn writeln(&mut self, s: &str) {
+        self.lines.push((self.time_ms, s.to_string()));
+    }
+}
+
+/// the firmware's USBOut, recording reports instead of sending them
+type SimOut = USBOut<
+    RecordingSink,
+    RecordingSink<ExtraReport>,
+    RecordingSink<MouseReport>,
+    DebugLog,
+>;
+
+fn new_output() -> SimOut {
+    USBOut::new(
+        RecordingSink::new(),
+        RecordingSink::new(),
+        RecordingSink::new(),
+        DebugLog {
+            time_ms: 0,
+            lines: Vec::new(),
+        },
+    )
+}
+
+fn set_time(output: &mut SimOut, time_ms: u32) {
+    output.time_ms = time_ms;
+    output.keyboard.sink.time_ms = time_ms;
+    output.extra.time_ms = time_ms;
+    output.mouse.time_ms = time_ms;
+    output.link.time_ms = time_ms;
+}
+
+fn record(log: &mut Vec<String>, time_ms: u32, kind: &str, what: String) {
+    log.push(format!("{:>7} {:<8} {}", time_ms, kind, what));
+}
+
+/// move everything the sinks recorded into the log, one line each
+fn drain(output: &mut SimOut, log: &mut Vec<String>) {
+    for (time_ms, report) in output.keyboard.sink.take() {
+        record(log, time_ms, "keyboard", format!("{:02x?}", report.as_bytes()));
+    }
+    for (time_ms, report) in output.extra.take() {
+        let kind = match report {
+            ExtraReport::Consumer(_) => "consumer",
+            ExtraReport::System(_) => "system",
+        };
+        record(log, time_ms, kind, format!("{:02x?}", report.as_bytes()));
+    }
+    for (time_ms, report) in output.mouse.take() {
+        record(log, time_ms, "mouse", format!("{:02x?}", report.as_bytes()));
+    }
+    for (time_ms, line) in output.link.lines.drain(..) {
+        record(log, time_ms, "debug", line);
+    }
+}
+
+/// (time ms, pressed, matrix index), sorted by time
+fn parse_script(text: &str) -> Result<Vec<(u32, bool, usize)>, String> {
+    let mut events = Vec::new();
+    for (ii, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = || format!("line {}: expected <time ms> press|release <matrix index>", ii + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(err());
+        }
+        let time = fields[0].parse::<u32>().map_err(|_| err())?;
+        let pressed = match fields[1] {
+            "press" => true,
+            "release" => false,
+            _ => return Err(err()),
+        };
+        let index = fields[2].parse::<usize>().map_err(|_| err())?;
+        if index >= board::SOURCE_COUNT * board::SINK_COUNT {
+            return Err(format!("line {}: matrix index {} out of range", ii + 1, index));
+        }
+        events.push((time, pressed, index));
+    }
+    events.sort_by_key(|e| e.0);
+    Ok(events)
+}
+
+/// Whether scan number `scans` is complete by time_ms.
+/// TIM3 completes scan n at n / rate_hz s, it is processed on the first ms
+/// at or after that - also for rates that don't divide 1000. build.rs keeps
+/// the rate at 1000 Hz at most, one scan per ms.
+fn scan_due(time_ms: u32, scans: u64, rate_hz: u32) -> bool {
+    u64::from(time_ms) * u64::from(rate_hz) >= scans * 1000
+}
+
+/// play back a script, returns the recorded reports
+fn simulate(events: &[(u32, bool, usize)]) -> Vec<String> {
+    let mut matrix: ScriptedMatrix<{ board::KEY_COUNT }> =
+        ScriptedMatrix::new(board::SOURCE_COUNT, board::SINK_COUNT);
+    let mut debouncer = board::Debouncer::new(
+        matrix.len(),
+        board::DEBOUNCE,
+        board::DEBOUNCE_KEYS,
+    );
+    let mut k2k = get_keytokey(new_output());
+    let mut log = Vec::new();
+    let mut pressed: Vec<usize> = Vec::new();
+    let mut next_event = 0;
+    let mut last_time_ms = 0;
+    let mut scans: u64 = 0;
+    let end_ms = events.last().map(|e| e.0).unwrap_or(0) + RUN_OUT_MS;
+
+    // one loop per TIM4 tick, scanning like TIM3
+    for time_ms in 0..=end_ms {
+        set_time(&mut k2k.output, time_ms);
+        k2k.output.tick_idle();
+        k2k.output.tick_mouse();
+        k2k.output.flush_buffer();
+        if !scan_due(time_ms, scans, board::SCAN_RATE_HZ) {
+            drain(&mut k2k.output, &mut log);
+            continue;
+        }
+        scans += 1;
+        while next_event < events.len() && events[next_event].0 <= time_ms {
+            let (_time, down, index) = events[next_event];
+            pressed.retain(|ii| *ii != index);
+            if down {
+                pressed.push(index);
+            }
+            next_event += 1;
+        }
+        matrix.push_scan(&pressed);
+        let states = matrix.scan();
+        let delta = (time_ms - last_time_ms).min(u16::MAX as u32) as u16;
+        if process_scan(states, &mut debouncer, TRANSLATION, &mut k2k, delta) {
+            last_time_ms = time_ms;
+        }
+        drain(&mut k2k.output, &mut log);
+    }
+    log
+}
+
+/// play back the script at path, returns the output as printed / stored in the golden file
+fn replay(path: &Path) -> Result<String, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let events = parse_script(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut output = format!("{}\n", board::NAME);
+    for line in simulate(&events) {
+        output.push_str(&line);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+enum Mode {
+    Print,
+    Check,
+    Bless,
+}
+
+/// compare against the golden file, returns whether they match
+fn check(output: &str, golden_path: &Path) -> bool {
+    let golden = match fs::read_to_string(golden_path) {
+        Ok(golden) => golden,
+        Err(e) => {
+            println!("{}: {} - run with --bless to create it", golden_path.display(), e);
+            return false;
+        }
+    };
+    if golden == output {
+        return true;
+    }
+    let expected: Vec<&str> = golden.lines().collect();
+    let actual: Vec<&str> = output.lines().collect();
+    let line = expected
+        .iter()
+        .zip(actual.iter())
+        .position(|(e, a)| e != a)
+        .unwrap_or_else(|| expected.len().min(actual.len()));
+    println!("{}: differs at line {}", golden_path.display(), line + 1);
+    println!("  expected: {}", expected.get(line).unwrap_or(&"<end of file>"));
+    println!("  actual:   {}", actual.get(line).unwrap_or(&"<end of output>"));
+    false
+}
+
+fn main() {
+    let mut mode = Mode::Print;
+    let mut scripts = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => mode = Mode::Check,
+            "--bless" => mode = Mode::Bless,
+            _ => scripts.push(arg),
+        }
+    }
+    if scripts.is_empty() {
+        eprintln!("usage: k2k_simulator [--check | --bless] <script>...");
+        process::exit(1);
+    }
+
+    let mut failed = 0;
+    for path in scripts.iter() {
+        let output = replay(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        let golden_path = Path::new(path).with_extension("golden");
+        match mode {
+            Mode::Print => print!("{}", output),
+            Mode::Check => {
+                if !check(&output, &golden_path) {
+                    failed += 1;
+                }
+            }
+            Mode::Bless => fs::write(&golden_path, output).unwrap_or_else(|e| {
+                eprintln!("could not write {}: {}", golden_path.display(), e);
+                process::exit(1);
+            }),
+        }
+    }
+    if let Mode::Check = mode {
+        println!("{} of {} scripts match their golden files", scripts.len() - failed, scripts.len());
+        if failed > 0 {
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the scripts are written for the Advantage's matrix
+    #[cfg(feature = "board-kinesis-advantage")]
+    /// the ms the first `count` scans are processed at
+    fn scan_times(rate_hz: u32, count: u64) -> Vec<u32> {
+        let mut scans = 0;
+        (0..)
+            .filter(|time_ms| {
+                let due = scan_due(*time_ms, scans, rate_hz);
+                scans += due as u64;
+                due
+            })
+            .take(count as usize)
+            .collect()
+    }
+
+    #[test]
+    fn scans_keep_the_exact_rate() {
+        assert_eq!(scan_times(100, 3), [0, 10, 20]);
+        // 1000 / 300 doesn't divide, the interval alternates instead of drifting
+        assert_eq!(scan_times(300, 7), [0, 4, 7, 10, 14, 17, 20]);
+        assert_eq!(scan_times(1000, 3), [0, 1, 2]);
+        assert_eq!(scan_times(1, 2), [0, 1000]);
+    }
+
+    #[test]
+    fn scripts_match_golden_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts");
+        let mut scripts: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| matches!(path.extension(), Some(ext) if ext == "txt"))
+            .collect();
+        scripts.sort();
+        assert!(!scripts.is_empty());
+        let failed: Vec<_> = scripts
+            .iter()
+            .filter(|script| {
+                let output = replay(script).unwrap();
+                !check(&output, &script.with_extension("golden"))
+            })
+            .collect();
+        assert!(failed.is_empty(), "differ from their golden files: {:?}", failed);
+    }
+}
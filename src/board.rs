@@ -0,0 +1,3 @@
+//! Board configuration, generated by build.rs from the layout file
+//! of the board selected with the board-* cargo features.
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
@@ -0,0 +1,44 @@
+use core::mem::MaybeUninit;
+use core::ptr;
+
+/// start of the STM32F103's system memory, which holds the
+/// factory serial bootloader (stack pointer, then reset vector)
+const SYSTEM_MEMORY: u32 = 0x1FFF_F000;
+const BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+
+/// survives a reset since .uninit is not zeroed by the startup code
+#[link_section = ".uninit.BOOTLOADER_FLAG"]
+static mut BOOTLOADER_FLAG: MaybeUninit<u32> = MaybeUninit::uninit();
+
+const SCB_AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+pub fn reset() -> ! {
+    cortex_m::asm::dsb();
+    unsafe {
+        ptr::write_volatile(SCB_AIRCR as *mut u32, AIRCR_SYSRESETREQ);
+    }
+    cortex_m::asm::dsb();
+    loop {}
+}
+
+/// Reset, and have the next boot jump into the serial bootloader
+/// (so stm32loader can flash without touching the BOOT0 jumper).
+pub fn reset_into_bootloader() -> ! {
+    unsafe {
+        ptr::write_volatile(BOOTLOADER_FLAG.as_mut_ptr(), BOOTLOADER_MAGIC);
+    }
+    reset()
+}
+
+/// To be called first thing in init, before any peripheral is set up.
+/// Jumps into the bootloader if reset_into_bootloader requested so.
+pub fn check_bootloader_flag() {
+    unsafe {
+        if ptr::read_volatile(BOOTLOADER_FLAG.as_ptr()) != BOOTLOADER_MAGIC {
+            return;
+        }
+        ptr::write_volatile(BOOTLOADER_FLAG.as_mut_ptr(), 0);
+        cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::bootloader;
+use crate::debounce::Debounce;
+use crate::keymap_store::FlashError;
+use crate::key_matrix::KeyStates;
+use crate::latency::Latency;
+use crate::trallocator::HeapUsage;
+use crate::StringSender;
+use keytokey::{HandlerID, KeyboardState};
+use no_std_compat::prelude::v1::*;
+
+const MAX_LINE_LEN: usize = 64;
+
+const HELP: &[&str] = &[
+    "matrix - list the pressed matrix positions",
+    "heap - show heap usage: in use, peak, size",
+    "latency - matrix edge to USB report latency, in ms",
+    "layers - list handlers and whether they are enabled",
+    "enable <id> / disable <id> - switch a handler on / off",
+    "get <index> - show the keycode of a matrix position",
+    "set <index> <keycode> - remap a released matrix position (decimal or 0x hex)",
+    "save - store the keymap in flash",
+    "default - go back to the compiled in keymap",
+    "reset - restart the firmware",
+    "bootloader - restart into the serial bootloader",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Help,
+    Matrix,
+    Heap,
+    Latency,
+    Layers,
+    Enable(HandlerID),
+    Disable(HandlerID),
+    Get(usize),
+    Set(usize, u32),
+    Save,
+    Default,
+    Reset,
+    Bootloader,
+}
+
+fn parse_number(s: Option<&str>) -> Option<u32> {
+    let s = s?;
+    if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse::<u32>().ok()
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, &'static str> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or("");
+        let first = parse_number(parts.next());
+        let second = parse_number(parts.next());
+        let handler_id = first.map(|id| id as HandlerID);
+        match (command, handler_id) {
+            ("help", _) => Ok(Command::Help),
+            ("matrix", _) => Ok(Command::Matrix),
+            ("heap", _) => Ok(Command::Heap),
+            ("latency", _) => Ok(Command::Latency),
+            ("layers", _) => Ok(Command::Layers),
+            ("enable", Some(id)) => Ok(Command::Enable(id)),
+            ("disable", Some(id)) => Ok(Command::Disable(id)),
+            ("enable", None) | ("disable", None) => Err("expected a handler id"),
+            ("get", _) => match first {
+                Some(index) => Ok(Command::Get(index as usize)),
+                None => Err("expected a matrix index"),
+            },
+            ("set", _) => match (first, second) {
+                (Some(index), Some(keycode)) => Ok(Command::Set(index as usize, keycode)),
+                _ => Err("expected a matrix index and a keycode"),
+            },
+            ("save", _) => Ok(Command::Save),
+            ("default", _) => Ok(Command::Default),
+            ("reset", _) => Ok(Command::Reset),
+            ("bootloader", _) => Ok(Command::Bootloader),
+            _ => Err("unknown command, try help"),
+        }
+    }
+}
+
+/// Collects bytes into lines, handling backspace.
+/// Lines longer than MAX_LINE_LEN are discarded.
+pub struct LineBuffer {
+    line: [u8; MAX_LINE_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> LineBuffer {
+        LineBuffer {
+            line: [0; MAX_LINE_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// returns the complete line once a line ending arrives
+    pub fn feed(&mut self, b: u8) -> Option<String> {
+        match b {
+            b'\r' | b'\n' => {
+                let line = if self.overflow {
+                    None
+                } else {
+                    core::str::from_utf8(&self.line[..self.len])
+                        .ok()
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                };
+                self.len = 0;
+                self.overflow = false;
+                line
+            }
+            0x08 | 0x7f => {
+                self.len = self.len.saturating_sub(1);
+                None
+            }
+            _ => {
+                if self.len < MAX_LINE_LEN {
+                    self.line[self.len] = b;
+                    self.len += 1;
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// collecting replies lets the caller decide where they go
+impl StringSender for Vec<String> {
+    fn writeln(&mut self, s: &str) {
+        self.push(s.to_string());
+    }
+}
+
+/// The line oriented command console, fed from the UART
+/// and the USB serial port. Each has its own line buffer.
+pub struct Console {
+    pub uart_line: LineBuffer,
+    pub usb_line: LineBuffer,
+    handler_count: usize,
+    save_requested: bool,
+}
+
+impl Console {
+    pub fn new(handler_count: usize) -> Console {
+        Console {
+            uart_line: LineBuffer::new(),
+            usb_line: LineBuffer::new(),
+            handler_count,
+            save_requested: false,
+        }
+    }
+
+    /// true once after a save command. The caller saves with
+    /// keymap_store::save outside of the K2K lock and replies with save_reply.
+    pub fn take_save_request(&mut self) -> bool {
+        core::mem::replace(&mut self.save_requested, false)
+    }
+
+    /// Execute one command line, returns the lines to reply with.
+    /// reset and bootloader don't return, save is left to the caller,
+    /// see take_save_request.
+    pub fn run<D: Debounce, const N: usize>(
+        &mut self,
+        line: &str,
+        matrix: &KeyStates<N>,
+        debouncer: &D,
+        heap: &HeapUsage,
+        latency: &Latency,
+        state: &mut KeyboardState,
+        keymap: &mut [u32],
+        default_keymap: &[u32],
+    ) -> Vec<String> {
+        let mut reply: Vec<String> = Vec::new();
+        match Command::parse(line) {
+            Ok(Command::Help) => {
+                for l in HELP {
+                    reply.writeln(l);
+                }
+            }
+            Ok(Command::Matrix) => {
+                for (ii, pressed) in matrix.iter().enumerate() {
+                    if pressed {
+                        reply.writeln(&format!("{}", ii));
+                    }
+                }
+            }
+            Ok(Command::Heap) => {
+                reply.writeln(&format!("heap {} peak {} of {}", heap.used, heap.peak, heap.size));
+                if heap.last_oom != 0 {
+                    reply.writeln(&format!("reset after running out allocating {}", heap.last_oom));
+                }
+            }
+            Ok(Command::Latency) => reply.writeln(&format!(
+                "latency last {} min {} avg {} max {} ({} reports)",
+                latency.last_ms,
+                latency.min_ms,
+                latency.average_ms(),
+                latency.max_ms,
+                latency.count
+            )),
+            Ok(Command::Layers) => {
+                for id in 0..self.handler_count {
+                    let enabled = if state.is_handler_enabled(id) { "on" } else { "off" };
+                    reply.writeln(&format!("{} {}", id, enabled));
+                }
+            }
+            Ok(Command::Enable(id)) | Ok(Command::Disable(id)) if id >= self.handler_count => {
+                reply.writeln("no such handler")
+            }
+            Ok(Command::Enable(id)) => {
+                state.enable_handler(id);
+                reply.writeln("ok");
+            }
+            Ok(Command::Disable(id)) => {
+                state.disable_handler(id);
+                reply.writeln("ok");
+            }
+            Ok(Command::Get(index)) => match keymap.get(index) {
+                Some(keycode) => reply.writeln(&format!("{} 0x{:x}", index, keycode)),
+                None => reply.writeln("no such matrix index"),
+            },
+            // its release would be translated with the new keycode,
+            // leaving the old one stuck on the host
+            Ok(Command::Set(index, _)) if debouncer.is_pressed(index) => {
+                reply.writeln("key is pressed, release it first")
+            }
+            Ok(Command::Set(index, keycode)) => match keymap.get_mut(index) {
+                Some(entry) => {
+                    *entry = keycode;
+                    reply.writeln("ok");
+                }
+                None => reply.writeln("no such matrix index"),
+            },
+            Ok(Command::Save) => self.save_requested = true,
+            Ok(Command::Default) => {
+                keymap.copy_from_slice(default_keymap);
+                reply.writeln("ok");
+            }
+            Ok(Command::Reset) => bootloader::reset(),
+            Ok(Command::Bootloader) => bootloader::reset_into_bootloader(),
+            Err(e) => reply.writeln(e),
+        }
+        reply
+    }
+}
+
+/// the reply to a save command
+pub fn save_reply(result: Result<(), FlashError>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => format!("saving failed: {:?}", e),
+    }
+}
@@ -0,0 +1,365 @@
+/// What a debouncer made of one raw reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebounceResult {
+    NoChange,
+    Pressed,
+    Released,
+}
+
+/// Scans a key has to be stable for, per direction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    pub press: u8,
+    pub release: u8,
+}
+
+/// A debouncing strategy, fed one raw reading per key and scan.
+///
+/// Thresholds count scans, so the time they stand for depends
+/// on the board's scan rate.
+pub trait Debounce {
+    /// `default` applies to all keys but those listed in `per_key` (matrix index, thresholds)
+    fn new(len: usize, default: Thresholds, per_key: &[(usize, Thresholds)]) -> Self
+    where
+        Self: Sized;
+
+    fn update(&mut self, index: usize, pressed: bool) -> DebounceResult;
+
+    /// the state last reported for the key, i.e. what keytokey was told
+    fn is_pressed(&self, index: usize) -> bool;
+}
+
+/// The fixed algorithm of the debouncing crate (Hackaday's "ultimate
+/// debouncing"), thresholds are ignored. The last 8 readings of a key are
+/// kept as bits: 3 pressed readings after 2 released ones are a press,
+/// and the other way round. N is the board's KEY_COUNT.
+pub struct LegacyDebouncer<const N: usize> {
+    patterns: [u8; N],
+    pressed: [bool; N],
+}
+
+impl<const N: usize> Debounce for LegacyDebouncer<N> {
+    fn new(len: usize, _default: Thresholds, _per_key: &[(usize, Thresholds)]) -> Self {
+        debug_assert!(len <= N);
+        LegacyDebouncer {
+            patterns: [0; N],
+            pressed: [false; N],
+        }
+    }
+
+    fn update(&mut self, index: usize, pressed: bool) -> DebounceResult {
+        let pattern = match self.patterns.get_mut(index) {
+            Some(pattern) => pattern,
+            None => return DebounceResult::NoChange,
+        };
+        *pattern = *pattern << 1 | pressed as u8;
+        // the 3 in the middle don't matter
+        match *pattern & 0b1100_0111 {
+            0b0000_0111 => {
+                *pattern = 0b0111_1111;
+                self.pressed[index] = true;
+                DebounceResult::Pressed
+            }
+            0b1100_0000 => {
+                *pattern = 0;
+                self.pressed[index] = false;
+                DebounceResult::Released
+            }
+            _ => DebounceResult::NoChange,
+        }
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        self.pressed.get(index).copied().unwrap_or(false)
+    }
+}
+
+fn thresholds_per_key<const N: usize>(
+    default: Thresholds,
+    per_key: &[(usize, Thresholds)],
+) -> [Thresholds; N] {
+    let mut thresholds = [default; N];
+    for (index, t) in per_key.iter().filter(|(index, _)| *index < N) {
+        thresholds[*index] = *t;
+    }
+    thresholds
+}
+
+#[derive(Clone, Copy, Default)]
+struct KeyState {
+    pressed: bool,
+    /// consecutive scans that disagreed with `pressed`
+    count: u8,
+}
+
+/// Deferred in both directions: a change is reported once the key
+/// read the new state for `press` / `release` consecutive scans.
+/// Equal thresholds make it symmetric. N is the board's KEY_COUNT.
+pub struct DeferredDebouncer<const N: usize> {
+    keys: [KeyState; N],
+    thresholds: [Thresholds; N],
+}
+
+impl<const N: usize> Debounce for DeferredDebouncer<N> {
+    fn new(len: usize, default: Thresholds, per_key: &[(usize, Thresholds)]) -> Self {
+        debug_assert!(len <= N);
+        DeferredDebouncer {
+            keys: [KeyState::default(); N],
+            thresholds: thresholds_per_key(default, per_key),
+        }
+    }
+
+    fn update(&mut self, index: usize, pressed: bool) -> DebounceResult {
+        let (key, thresholds) = match (self.keys.get_mut(index), self.thresholds.get(index)) {
+            (Some(key), Some(thresholds)) => (key, thresholds),
+            _ => return DebounceResult::NoChange,
+        };
+        if pressed == key.pressed {
+            key.count = 0;
+            return DebounceResult::NoChange;
+        }
+        key.count = key.count.saturating_add(1);
+        let threshold = if pressed { thresholds.press } else { thresholds.release };
+        if key.count < threshold {
+            return DebounceResult::NoChange;
+        }
+        key.pressed = pressed;
+        key.count = 0;
+        if pressed {
+            DebounceResult::Pressed
+        } else {
+            DebounceResult::Released
+        }
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        matches!(self.keys.get(index), Some(key) if key.pressed)
+    }
+}
+
+/// Eager press, deferred release: a press is reported on the first
+/// scan that sees it. A release has to be read for `release`
+/// consecutive scans, which also swallows chatter while held.
+/// After either change the key is ignored for `press` scans
+/// to ride out the bounce. N is the board's KEY_COUNT.
+pub struct EagerDebouncer<const N: usize> {
+    keys: [EagerKeyState; N],
+    thresholds: [Thresholds; N],
+}
+
+#[derive(Clone, Copy, Default)]
+struct EagerKeyState {
+    pressed: bool,
+    /// scans left before the key is looked at again
+    lockout: u8,
+    /// consecutive released scans while pressed
+    released_for: u8,
+}
+
+impl<const N: usize> Debounce for EagerDebouncer<N> {
+    fn new(len: usize, default: Thresholds, per_key: &[(usize, Thresholds)]) -> Self {
+        debug_assert!(len <= N);
+        EagerDebouncer {
+            keys: [EagerKeyState::default(); N],
+            thresholds: thresholds_per_key(default, per_key),
+        }
+    }
+
+    fn update(&mut self, index: usize, pressed: bool) -> DebounceResult {
+        let (key, thresholds) = match (self.keys.get_mut(index), self.thresholds.get(index)) {
+            (Some(key), Some(thresholds)) => (key, thresholds),
+            _ => return DebounceResult::NoChange,
+        };
+        if key.lockout > 0 {
+            key.lockout -= 1;
+            return DebounceResult::NoChange;
+        }
+        match (key.pressed, pressed) {
+            (false, true) => {
+                key.pressed = true;
+                key.lockout = thresholds.press;
+                key.released_for = 0;
+                DebounceResult::Pressed
+            }
+            (true, false) => {
+                key.released_for = key.released_for.saturating_add(1);
+                if key.released_for < thresholds.release {
+                    return DebounceResult::NoChange;
+                }
+                key.pressed = false;
+                key.lockout = thresholds.press;
+                key.released_for = 0;
+                DebounceResult::Released
+            }
+            _ => {
+                key.released_for = 0;
+                DebounceResult::NoChange
+            }
+        }
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        matches!(self.keys.get(index), Some(key) if key.pressed)
+    }
+}
+
+/// Up/down counter per key: scans reading the new state count up,
+/// scans reading the current one count down (not below 0).
+/// The change is reported once the count reaches `press` / `release`,
+/// so occasional noise slows a change down instead of restarting it.
+/// N is the board's KEY_COUNT.
+pub struct CounterDebouncer<const N: usize> {
+    keys: [KeyState; N],
+    thresholds: [Thresholds; N],
+}
+
+impl<const N: usize> Debounce for CounterDebouncer<N> {
+    fn new(len: usize, default: Thresholds, per_key: &[(usize, Thresholds)]) -> Self {
+        debug_assert!(len <= N);
+        CounterDebouncer {
+            keys: [KeyState::default(); N],
+            thresholds: thresholds_per_key(default, per_key),
+        }
+    }
+
+    fn update(&mut self, index: usize, pressed: bool) -> DebounceResult {
+        let (key, thresholds) = match (self.keys.get_mut(index), self.thresholds.get(index)) {
+            (Some(key), Some(thresholds)) => (key, thresholds),
+            _ => return DebounceResult::NoChange,
+        };
+        if pressed == key.pressed {
+            key.count = key.count.saturating_sub(1);
+            return DebounceResult::NoChange;
+        }
+        key.count = key.count.saturating_add(1);
+        let threshold = if pressed { thresholds.press } else { thresholds.release };
+        if key.count < threshold {
+            return DebounceResult::NoChange;
+        }
+        key.pressed = pressed;
+        key.count = 0;
+        if pressed {
+            DebounceResult::Pressed
+        } else {
+            DebounceResult::Released
+        }
+    }
+
+    fn is_pressed(&self, index: usize) -> bool {
+        matches!(self.keys.get(index), Some(key) if key.pressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::DebounceResult::{NoChange, Pressed, Released};
+    use no_std_compat::prelude::v1::*;
+
+    fn thresholds(press: u8, release: u8) -> Thresholds {
+        Thresholds { press, release }
+    }
+
+    /// feed key 0 one reading per scan
+    fn feed<D: Debounce>(debouncer: &mut D, readings: &[u8]) -> Vec<DebounceResult> {
+        readings.iter().map(|r| debouncer.update(0, *r == 1)).collect()
+    }
+
+    #[test]
+    fn legacy_needs_three_scans() {
+        let mut d = LegacyDebouncer::<1>::new(1, thresholds(0, 0), &[]);
+        assert_eq!(
+            feed(&mut d, &[1, 1, 1, 0, 0, 0]),
+            [NoChange, NoChange, Pressed, NoChange, NoChange, Released]
+        );
+        // a single pressed scan is noise, and stays in the pattern for a while
+        assert_eq!(feed(&mut d, &[1, 0, 0, 0, 0, 0, 0]), [NoChange; 7]);
+        // chatter while held doesn't release, three more pressed scans don't press again
+        assert_eq!(feed(&mut d, &[1, 1, 1]), [NoChange, NoChange, Pressed]);
+        assert_eq!(feed(&mut d, &[0, 0, 1, 1, 1]), [NoChange; 5]);
+    }
+
+    #[test]
+    fn deferred_needs_consecutive_scans() {
+        let mut d = DeferredDebouncer::<2>::new(2, thresholds(3, 2), &[]);
+        // chatter restarts the count
+        assert_eq!(
+            feed(&mut d, &[1, 1, 0, 1, 1, 1, 1]),
+            [NoChange, NoChange, NoChange, NoChange, NoChange, Pressed, NoChange]
+        );
+        assert_eq!(feed(&mut d, &[0, 1, 0, 0, 0]), [NoChange, NoChange, NoChange, Released, NoChange]);
+        // the other key is untouched
+        assert_eq!(d.update(1, false), NoChange);
+    }
+
+    #[test]
+    fn deferred_threshold_0_reports_at_once() {
+        let mut d = DeferredDebouncer::<1>::new(1, thresholds(0, 0), &[]);
+        assert_eq!(feed(&mut d, &[1, 0, 1, 1, 0]), [Pressed, Released, Pressed, NoChange, Released]);
+    }
+
+    #[test]
+    fn per_key_thresholds() {
+        let mut d = DeferredDebouncer::<2>::new(2, thresholds(1, 1), &[(0, thresholds(3, 1))]);
+        assert_eq!(feed(&mut d, &[1, 1, 1]), [NoChange, NoChange, Pressed]);
+        assert_eq!(d.update(1, true), Pressed);
+        // out of range entries and indices are ignored
+        let mut d = DeferredDebouncer::<1>::new(1, thresholds(1, 1), &[(5, thresholds(3, 3))]);
+        assert_eq!(d.update(0, true), Pressed);
+        assert_eq!(d.update(5, true), NoChange);
+    }
+
+    #[test]
+    fn eager_press_then_lockout() {
+        let mut d = EagerDebouncer::<1>::new(1, thresholds(2, 2), &[]);
+        // press at once, the bounce during the lockout is ignored
+        assert_eq!(feed(&mut d, &[1, 0, 0, 1]), [Pressed, NoChange, NoChange, NoChange]);
+        // chatter while held: a single released scan is swallowed
+        assert_eq!(feed(&mut d, &[0, 1, 0, 1]), [NoChange, NoChange, NoChange, NoChange]);
+        // release after two released scans, then locked out again
+        assert_eq!(feed(&mut d, &[0, 0, 1, 1, 1]), [NoChange, Released, NoChange, NoChange, Pressed]);
+    }
+
+    #[test]
+    fn eager_threshold_0_has_no_lockout() {
+        let mut d = EagerDebouncer::<1>::new(1, thresholds(0, 0), &[]);
+        assert_eq!(feed(&mut d, &[1, 0, 1, 0, 0]), [Pressed, Released, Pressed, Released, NoChange]);
+        // release 1 still reacts on the first released scan, press 0 keeps it unlocked
+        let mut d = EagerDebouncer::<1>::new(1, thresholds(0, 1), &[]);
+        assert_eq!(feed(&mut d, &[1, 0, 1]), [Pressed, Released, Pressed]);
+    }
+
+    #[test]
+    fn counter_tolerates_noise() {
+        let mut d = CounterDebouncer::<1>::new(1, thresholds(3, 3), &[]);
+        // deferred would restart on the 0, the counter only steps back
+        assert_eq!(
+            feed(&mut d, &[1, 1, 0, 1, 1]),
+            [NoChange, NoChange, NoChange, NoChange, Pressed]
+        );
+        // noise alone never gets there
+        assert_eq!(feed(&mut d, &[0, 1, 0, 1, 0, 1]), [NoChange; 6]);
+        assert_eq!(feed(&mut d, &[0, 0, 0]), [NoChange, NoChange, Released]);
+    }
+
+    /// is_pressed follows the reported changes, not the raw readings
+    fn check_is_pressed<D: Debounce>(mut d: D) {
+        assert!(!d.is_pressed(0));
+        while d.update(0, true) != Pressed {}
+        assert!(d.is_pressed(0));
+        // released, but not reported yet
+        d.update(0, false);
+        assert!(d.is_pressed(0));
+        while d.update(0, false) != Released {}
+        assert!(!d.is_pressed(0));
+        assert!(!d.is_pressed(5));
+    }
+
+    #[test]
+    fn is_pressed_is_the_reported_state() {
+        check_is_pressed(LegacyDebouncer::<1>::new(1, thresholds(0, 0), &[]));
+        check_is_pressed(DeferredDebouncer::<1>::new(1, thresholds(2, 2), &[]));
+        check_is_pressed(EagerDebouncer::<1>::new(1, thresholds(2, 2), &[]));
+        check_is_pressed(CounterDebouncer::<1>::new(1, thresholds(2, 2), &[]));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::hid::{ConsumerUsage, SystemUsage};
+use crate::keyboard::KeyboardLeds;
+use crate::mouse::MouseAction;
+use keytokey::{Event, EventStatus, ProcessKeys, USBKeyOut};
+use no_std_compat::prelude::v1::*;
+
+/// Output features beyond what keytokey's USBKeyOut offers,
+/// for handlers set up in get_keytokey
+pub trait ExtendedKeyOut: USBKeyOut {
+    /// the LED state (num lock, caps lock...) last sent by the host
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
+    fn host_leds(&self) -> KeyboardLeds;
+
+    /// report this consumer page usage as pressed, 0 releases it
+    fn send_consumer(&mut self, usage: u16);
+
+    /// report this system control usage as pressed, 0 releases it
+    fn send_system(&mut self, usage: u8);
+
+    /// a mouse key was pressed (or released)
+    fn mouse_action(&mut self, action: MouseAction, pressed: bool);
+}
+
+/// Keycodes from CONSUMER_KEYCODE_BASE upwards are consumer page usages.
+/// They start beyond the last unicode codepoint, so they can be
+/// used in TRANSLATION and layers without clashing with UnicodeKeyboard.
+pub const CONSUMER_KEYCODE_BASE: u32 = 0x11_0000;
+const CONSUMER_KEYCODE_END: u32 = CONSUMER_KEYCODE_BASE + 0x400;
+/// Keycodes from SYSTEM_KEYCODE_BASE upwards are system control usages.
+pub const SYSTEM_KEYCODE_BASE: u32 = 0x11_1000;
+const SYSTEM_KEYCODE_END: u32 = SYSTEM_KEYCODE_BASE + 0x100;
+/// Keycodes from MOUSE_KEYCODE_BASE upwards are mouse keys.
+pub const MOUSE_KEYCODE_BASE: u32 = 0x11_2000;
+
+/// the keycode that sends this consumer usage
+pub const fn consumer_keycode(usage: ConsumerUsage) -> u32 {
+    CONSUMER_KEYCODE_BASE + usage as u32
+}
+
+/// the keycode that sends this system control usage
+pub const fn system_keycode(usage: SystemUsage) -> u32 {
+    SYSTEM_KEYCODE_BASE + usage as u32
+}
+
+/// the keycode that triggers this mouse key
+pub const fn mouse_keycode(action: MouseAction) -> u32 {
+    MOUSE_KEYCODE_BASE + action as u32
+}
+
+fn to_mouse_action(keycode: u32) -> Option<MouseAction> {
+    if (MOUSE_KEYCODE_BASE..MOUSE_KEYCODE_BASE + 0x100).contains(&keycode) {
+        MouseAction::new((keycode - MOUSE_KEYCODE_BASE) as u8)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExtraKey {
+    Consumer(u16),
+    System(u8),
+}
+
+impl ExtraKey {
+    fn from_keycode(keycode: u32) -> Option<ExtraKey> {
+        if (CONSUMER_KEYCODE_BASE..CONSUMER_KEYCODE_END).contains(&keycode) {
+            Some(ExtraKey::Consumer((keycode - CONSUMER_KEYCODE_BASE) as u16))
+        } else if (SYSTEM_KEYCODE_BASE..SYSTEM_KEYCODE_END).contains(&keycode) {
+            Some(ExtraKey::System((keycode - SYSTEM_KEYCODE_BASE) as u8))
+        } else {
+            None
+        }
+    }
+}
+
+/// usages held at once per report, more forget the oldest
+const HELD_MAX: usize = 8;
+
+/// The usages currently held, in the order they were pressed
+struct Held<T: Copy + PartialEq + Default> {
+    usages: [T; HELD_MAX],
+    len: usize,
+}
+
+impl<T: Copy + PartialEq + Default> Held<T> {
+    fn new() -> Held<T> {
+        Held {
+            usages: [T::default(); HELD_MAX],
+            len: 0,
+        }
+    }
+
+    /// the most recently pressed usage still held
+    fn top(&self) -> Option<T> {
+        self.usages[..self.len].last().cloned()
+    }
+
+    fn press(&mut self, usage: T) {
+        self.release(usage);
+        if self.len == HELD_MAX {
+            self.usages.copy_within(1.., 0);
+            self.len -= 1;
+        }
+        self.usages[self.len] = usage;
+        self.len += 1;
+    }
+
+    /// returns whether the usage was held
+    fn release(&mut self, usage: T) -> bool {
+        match self.usages[..self.len].iter().position(|u| *u == usage) {
+            Some(index) => {
+                self.usages.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sends consumer control reports (volume, play/pause...) and
+/// system control reports (sleep, wake...) for keycodes created
+/// with consumer_keycode / system_keycode.
+///
+/// Only one usage per report is sent at a time,
+/// the most recently pressed one still held wins.
+pub struct ExtraKeysHandler {
+    held_consumer: Held<u16>,
+    held_system: Held<u8>,
+}
+
+impl ExtraKeysHandler {
+    pub fn new() -> ExtraKeysHandler {
+        ExtraKeysHandler {
+            held_consumer: Held::new(),
+            held_system: Held::new(),
+        }
+    }
+}
+
+impl<T: ExtendedKeyOut> ProcessKeys<T> for ExtraKeysHandler {
+    fn process_keys(&mut self, events: &mut Vec<(Event, EventStatus)>, output: &mut T) {
+        for (event, status) in events.iter_mut() {
+            match status {
+                EventStatus::Unhandled => {}
+                _ => continue,
+            }
+            match event {
+                Event::KeyPress(key) => match ExtraKey::from_keycode(key.keycode) {
+                    Some(ExtraKey::Consumer(usage)) => {
+                        self.held_consumer.press(usage);
+                        output.send_consumer(usage);
+                        *status = EventStatus::Handled;
+                    }
+                    Some(ExtraKey::System(usage)) => {
+                        self.held_system.press(usage);
+                        output.send_system(usage);
+                        *status = EventStatus::Handled;
+                    }
+                    None => {}
+                },
+                // releasing the usage being sent falls back to the one
+                // pressed before it, or to 0 once none is held
+                Event::KeyRelease(key) => match ExtraKey::from_keycode(key.keycode) {
+                    Some(ExtraKey::Consumer(usage)) => {
+                        let sent = self.held_consumer.top();
+                        if self.held_consumer.release(usage) && sent == Some(usage) {
+                            output.send_consumer(self.held_consumer.top().unwrap_or(0));
+                        }
+                        *status = EventStatus::Handled;
+                    }
+                    Some(ExtraKey::System(usage)) => {
+                        let sent = self.held_system.top();
+                        if self.held_system.release(usage) && sent == Some(usage) {
+                            output.send_system(self.held_system.top().unwrap_or(0));
+                        }
+                        *status = EventStatus::Handled;
+                    }
+                    None => {}
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Mouse keys: moves the cursor, scrolls and clicks
+/// for keycodes created with mouse_keycode.
+/// The actual movement (and acceleration) happens in USBOut::tick_mouse.
+pub struct MouseKeysHandler {}
+
+impl<T: ExtendedKeyOut> ProcessKeys<T> for MouseKeysHandler {
+    fn process_keys(&mut self, events: &mut Vec<(Event, EventStatus)>, output: &mut T) {
+        for (event, status) in events.iter_mut() {
+            match status {
+                EventStatus::Unhandled => {}
+                _ => continue,
+            }
+            match event {
+                Event::KeyPress(key) => {
+                    if let Some(action) = to_mouse_action(key.keycode) {
+                        output.mouse_action(action, true);
+                        *status = EventStatus::Handled;
+                    }
+                }
+                Event::KeyRelease(key) => {
+                    if let Some(action) = to_mouse_action(key.keycode) {
+                        output.mouse_action(action, false);
+                        *status = EventStatus::Handled;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keytokey::{KeyCode, Keyboard, KeyboardState};
+
+    /// records the consumer and system usages sent
+    struct Recorder {
+        state: KeyboardState,
+        consumer: Vec<u16>,
+        system: Vec<u8>,
+    }
+
+    impl USBKeyOut for Recorder {
+        fn send_keys(&mut self, _keys: &[KeyCode]) {}
+        fn register_key(&mut self, _key: KeyCode) {}
+        fn send_registered(&mut self) {}
+        fn send_empty(&mut self) {}
+        fn state(&mut self) -> &mut KeyboardState {
+            &mut self.state
+        }
+        fn ro_state(&self) -> &KeyboardState {
+            &self.state
+        }
+        fn debug(&mut self, _s: &str) {}
+    }
+
+    impl ExtendedKeyOut for Recorder {
+        fn host_leds(&self) -> KeyboardLeds {
+            KeyboardLeds::default()
+        }
+        fn send_consumer(&mut self, usage: u16) {
+            self.consumer.push(usage);
+        }
+        fn send_system(&mut self, usage: u8) {
+            self.system.push(usage);
+        }
+        fn mouse_action(&mut self, _action: MouseAction, _pressed: bool) {}
+    }
+
+    fn keyboard<'a>() -> Keyboard<'a, Recorder> {
+        let mut k = Keyboard::new(Recorder {
+            state: KeyboardState::new(),
+            consumer: Vec::new(),
+            system: Vec::new(),
+        });
+        k.add_handler(Box::new(ExtraKeysHandler::new()));
+        k
+    }
+
+    fn press(k: &mut Keyboard<Recorder>, keycode: u32) {
+        k.add_keypress(keycode, 10);
+        k.handle_keys().ok();
+        k.clear_unhandled();
+    }
+
+    fn release(k: &mut Keyboard<Recorder>, keycode: u32) {
+        k.add_keyrelease(keycode, 10);
+        k.handle_keys().ok();
+        k.clear_unhandled();
+    }
+
+    #[test]
+    fn overlapping_presses_release_in_press_order() {
+        let up = consumer_keycode(ConsumerUsage::VolumeUp);
+        let mute = consumer_keycode(ConsumerUsage::Mute);
+        let mut k = keyboard();
+        press(&mut k, up);
+        press(&mut k, mute);
+        release(&mut k, up);
+        release(&mut k, mute);
+        // releasing the older key changes nothing, the last release sends 0
+        assert_eq!(k.output.consumer, [0xE9, 0xE2, 0]);
+    }
+
+    #[test]
+    fn overlapping_presses_release_newest_first() {
+        let up = consumer_keycode(ConsumerUsage::VolumeUp);
+        let mute = consumer_keycode(ConsumerUsage::Mute);
+        let sleep = system_keycode(SystemUsage::Sleep);
+        let mut k = keyboard();
+        press(&mut k, up);
+        press(&mut k, mute);
+        press(&mut k, sleep);
+        release(&mut k, mute);
+        // volume up is still held, so it is sent again
+        release(&mut k, up);
+        release(&mut k, sleep);
+        assert_eq!(k.output.consumer, [0xE9, 0xE2, 0xE9, 0]);
+        assert_eq!(k.output.system, [0x82, 0]);
+    }
+
+    #[test]
+    fn held_forgets_the_oldest_when_full() {
+        let mut held = Held::<u8>::new();
+        for usage in 1..=HELD_MAX as u8 + 1 {
+            held.press(usage);
+        }
+        assert!(!held.release(1));
+        assert!(held.release(HELD_MAX as u8 + 1));
+        assert_eq!(held.top(), Some(HELD_MAX as u8));
+        // pressing a held usage again moves it to the top
+        held.press(2);
+        assert_eq!(held.top(), Some(2));
+        assert!(held.release(2));
+        assert!(!held.release(2));
+    }
+}
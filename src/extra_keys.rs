@@ -0,0 +1,150 @@
+use crate::hid::{
+    ConsumerReport, ExtraReport, HidClass, HidDevice, Protocol, ReportType, Subclass,
+    SystemReport, REPORT_ID_CONSUMER, REPORT_ID_SYSTEM,
+};
+use crate::report_sink::ReportSink;
+use usb_device::bus::UsbBus;
+
+// Consumer control, report ID 1: one 16 bit usage (array) from the consumer page.
+// System control, report ID 2: one 8 bit usage (array) from the generic desktop page.
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, REPORT_ID_CONSUMER, 0x15, 0x00, 0x26, 0xFF, 0x03,
+    0x19, 0x00, 0x2A, 0xFF, 0x03, 0x75, 0x10, 0x95, 0x01, 0x81, 0x00, 0xC0, 0x05, 0x01, 0x09,
+    0x80, 0xA1, 0x01, 0x85, REPORT_ID_SYSTEM, 0x15, 0x00, 0x26, 0xB7, 0x00, 0x19, 0x00, 0x29,
+    0xB7, 0x75, 0x08, 0x95, 0x01, 0x81, 0x00, 0xC0,
+];
+
+/// The keys that don't fit into the keyboard report,
+/// i.e. media keys and power/sleep/wake.
+/// Lives on its own interface so the keyboard interface can stay
+/// boot protocol compatible.
+pub struct ExtraKeys {
+    consumer_report: ConsumerReport,
+    system_report: SystemReport,
+}
+
+impl ExtraKeys {
+    pub fn new() -> ExtraKeys {
+        ExtraKeys {
+            consumer_report: ConsumerReport::default(),
+            system_report: SystemReport::default(),
+        }
+    }
+
+    fn report_sent(&mut self, report: &ExtraReport) {
+        match report {
+            ExtraReport::Consumer(report) => self.consumer_report = *report,
+            ExtraReport::System(report) => self.system_report = *report,
+        }
+    }
+}
+
+impl HidDevice for ExtraKeys {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        REPORT_DESCRIPTOR
+    }
+
+    fn report_ids(&self) -> &[u8] {
+        &[REPORT_ID_CONSUMER, REPORT_ID_SYSTEM]
+    }
+
+    fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], ()> {
+        match (report_type, report_id) {
+            (ReportType::Input, REPORT_ID_CONSUMER) => Ok(self.consumer_report.as_bytes()),
+            (ReportType::Input, REPORT_ID_SYSTEM) => Ok(self.system_report.as_bytes()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), ()> {
+        Err(())
+    }
+}
+
+/// reports are remembered once the endpoint accepted them
+impl<'a, B: UsbBus> ReportSink<ExtraReport> for HidClass<'a, B, ExtraKeys> {
+    fn write_report(&mut self, report: &ExtraReport) -> Result<usize, ()> {
+        let written = self.write(report.as_bytes());
+        if let Ok(count) = written {
+            if count > 0 {
+                self.device_mut().report_sent(report);
+            }
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::SystemUsage;
+
+    /// the system control collection, i.e. everything after the consumer collection
+    fn system_collection() -> &'static [u8] {
+        let end_of_consumer = REPORT_DESCRIPTOR.iter().position(|b| *b == 0xC0).unwrap();
+        &REPORT_DESCRIPTOR[end_of_consumer + 1..]
+    }
+
+    #[test]
+    fn system_control_descriptor() {
+        assert_eq!(
+            system_collection(),
+            &[
+                0x05, 0x01, // usage page (generic desktop)
+                0x09, 0x80, // usage (system control)
+                0xA1, 0x01, // collection (application)
+                0x85, 0x02, // report ID (2)
+                0x15, 0x00, // logical minimum (0)
+                0x26, 0xB7, 0x00, // logical maximum (0xB7)
+                0x19, 0x00, // usage minimum (0)
+                0x29, 0xB7, // usage maximum (0xB7)
+                0x75, 0x08, // report size (8)
+                0x95, 0x01, // report count (1)
+                0x81, 0x00, // input (data, array, absolute)
+                0xC0, // end collection
+            ][..]
+        );
+        assert_eq!(REPORT_ID_SYSTEM, 2);
+    }
+
+    #[test]
+    fn system_report_payloads() {
+        for (usage, byte) in &[
+            (SystemUsage::PowerDown, 0x81),
+            (SystemUsage::Sleep, 0x82),
+            (SystemUsage::WakeUp, 0x83),
+        ] {
+            let report = SystemReport::new(*usage as u8);
+            assert_eq!(report.as_bytes(), &[REPORT_ID_SYSTEM, *byte]);
+            assert_eq!(ExtraReport::System(report).as_bytes(), &[2, *byte]);
+        }
+        assert_eq!(SystemReport::default().as_bytes(), &[2, 0]);
+    }
+
+    #[test]
+    fn get_report_returns_last_sent() {
+        let mut keys = ExtraKeys::new();
+        assert_eq!(keys.get_report(ReportType::Input, REPORT_ID_SYSTEM), Ok(&[2u8, 0][..]));
+        keys.report_sent(&ExtraReport::System(SystemReport::new(SystemUsage::Sleep as u8)));
+        keys.report_sent(&ExtraReport::Consumer(ConsumerReport::new(0xE9)));
+        assert_eq!(keys.get_report(ReportType::Input, REPORT_ID_SYSTEM), Ok(&[2u8, 0x82][..]));
+        assert_eq!(
+            keys.get_report(ReportType::Input, REPORT_ID_CONSUMER),
+            Ok(&[1u8, 0xE9, 0][..])
+        );
+        assert_eq!(keys.get_report(ReportType::Feature, REPORT_ID_SYSTEM), Err(()));
+    }
+}
@@ -12,6 +12,10 @@ use usb_device::UsbError;
 
 const SPECIFICATION_RELEASE: u16 = 0x111;
 const INTERFACE_CLASS_HID: u8 = 0x03;
+/// large enough for every report but the keyboard's NKRO report, see Keyboard
+pub const DEFAULT_MAX_PACKET_SIZE: u16 = 32;
+/// most report IDs an interface may declare, see HidDevice::report_ids
+pub const MAX_REPORT_IDS: usize = 2;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
@@ -28,6 +32,25 @@ pub enum Protocol {
     Mouse = 0x02,
 }
 
+/// The protocol selected by the host with SET_PROTOCOL.
+/// Devices start out in report protocol after a reset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ReportProtocol {
+    Boot = 0x00,
+    Report = 0x01,
+}
+
+impl ReportProtocol {
+    fn new(u: u8) -> Option<ReportProtocol> {
+        match u {
+            0x00 => Some(ReportProtocol::Boot),
+            0x01 => Some(ReportProtocol::Report),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum DescriptorType {
@@ -91,6 +114,20 @@ pub trait HidDevice {
         -> Result<(), ()>;
 
     fn get_report(&mut self, report_type: ReportType, report_id: u8) -> Result<&[u8], ()>;
+
+    /// The input report IDs declared in the report descriptor,
+    /// at most MAX_REPORT_IDS. Interfaces without report IDs use 0.
+    fn report_ids(&self) -> &[u8] {
+        &[0]
+    }
+
+    /// called whenever the host switches between boot and report protocol
+    fn set_protocol(&mut self, _protocol: ReportProtocol) {}
+
+    /// max packet size of the interrupt in endpoint, i.e. the largest report
+    fn max_packet_size(&self) -> u16 {
+        DEFAULT_MAX_PACKET_SIZE
+    }
 }
 
 pub struct HidClass<'a, B: UsbBus, D: HidDevice> {
@@ -98,18 +135,100 @@ pub struct HidClass<'a, B: UsbBus, D: HidDevice> {
     interface: InterfaceNumber,
     endpoint_interrupt_in: EndpointIn<'a, B>,
     expect_interrupt_in_complete: bool,
+    protocol: ReportProtocol,
+    idle: IdleTimers,
+}
+
+/// The idle rate set by the host for each report ID, and the time since
+/// a report with that ID was written. Indexed like HidDevice::report_ids.
+#[derive(Clone, Copy, Default)]
+struct IdleTimers {
+    /// in units of 4 ms, 0 = only report on change
+    rates: [u8; MAX_REPORT_IDS],
+    /// milliseconds since the last report was written
+    elapsed_ms: [u16; MAX_REPORT_IDS],
+}
+
+impl IdleTimers {
+    /// SET_IDLE, report ID 0 sets every report ID.
+    /// Err for report IDs the interface does not declare.
+    fn set(&mut self, report_ids: &[u8], report_id: u8, rate: u8) -> Result<(), ()> {
+        let count = report_ids.len().min(MAX_REPORT_IDS);
+        for (ii, id) in report_ids[..count].iter().enumerate() {
+            if report_id == 0 || *id == report_id {
+                self.rates[ii] = rate;
+                self.elapsed_ms[ii] = 0;
+            }
+        }
+        if report_id == 0 || report_ids[..count].contains(&report_id) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// GET_IDLE, None for report IDs the interface does not declare
+    fn get(&self, report_ids: &[u8], report_id: u8) -> Option<u8> {
+        let index = report_ids.iter().position(|id| *id == report_id)?;
+        self.rates.get(index).cloned()
+    }
+
+    /// a report with this ID was written, restart its idle period
+    fn written(&mut self, report_ids: &[u8], report_id: u8) {
+        if let Some(index) = report_ids.iter().position(|id| *id == report_id) {
+            if let Some(elapsed) = self.elapsed_ms.get_mut(index) {
+                *elapsed = 0;
+            }
+        }
+    }
+
+    /// Advance every idle period by one millisecond,
+    /// returns the first report ID whose period has elapsed.
+    fn tick(&mut self, report_ids: &[u8]) -> Option<u8> {
+        let mut due = None;
+        for ((id, rate), elapsed) in report_ids
+            .iter()
+            .zip(self.rates.iter())
+            .zip(self.elapsed_ms.iter_mut())
+        {
+            if *rate == 0 {
+                continue;
+            }
+            *elapsed = elapsed.saturating_add(1);
+            if due.is_none() && *elapsed >= *rate as u16 * 4 {
+                due = Some(*id);
+            }
+        }
+        due
+    }
 }
 
 impl<B: UsbBus, D: HidDevice> HidClass<'_, B, D> {
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
     pub fn new(device: D, alloc: &UsbBusAllocator<B>) -> HidClass<'_, B, D> {
+        let max_packet_size = device.max_packet_size();
         HidClass {
             device,
             interface: alloc.interface(),
-            endpoint_interrupt_in: alloc.interrupt(8, 10),
+            endpoint_interrupt_in: alloc.interrupt(max_packet_size, 10),
             expect_interrupt_in_complete: false,
+            protocol: ReportProtocol::Report,
+            idle: IdleTimers::default(),
         }
     }
 
+    /// the protocol currently selected by the host
+    pub fn protocol(&self) -> ReportProtocol {
+        self.protocol
+    }
+
     pub fn write(&mut self, data: &[u8]) -> Result<usize, ()> {
         if self.expect_interrupt_in_complete {
             return Ok(0);
@@ -120,12 +239,28 @@ impl<B: UsbBus, D: HidDevice> HidClass<'_, B, D> {
         }
 
         match self.endpoint_interrupt_in.write(data) {
-            Ok(count) => Ok(count),
+            Ok(count) => {
+                // reports of interfaces with report IDs start with the ID
+                let report_ids = self.device.report_ids();
+                let report_id = match (report_ids, data.first()) {
+                    ([0], _) | (_, None) => 0,
+                    (_, Some(id)) => *id,
+                };
+                self.idle.written(report_ids, report_id);
+                Ok(count)
+            }
             Err(UsbError::WouldBlock) => Ok(0),
             Err(_) => Err(()),
         }
     }
 
+    /// Advance the idle timers by one millisecond.
+    /// Returns the report ID whose idle period set by the host has elapsed
+    /// without a report being written, i.e. whose last report should be repeated.
+    pub fn tick_idle(&mut self) -> Option<u8> {
+        self.idle.tick(self.device.report_ids())
+    }
+
     fn get_report(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
         let [report_type, report_id] = req.value.to_be_bytes();
@@ -145,6 +280,41 @@ impl<B: UsbBus, D: HidDevice> HidClass<'_, B, D> {
             Err(()) => xfer.reject().ok(),
         };
     }
+
+    fn get_idle(&mut self, xfer: ControlIn<B>) {
+        let report_id = xfer.request().value as u8;
+        match self.idle.get(self.device.report_ids(), report_id) {
+            Some(rate) => xfer.accept_with(&[rate]).ok(),
+            None => xfer.reject().ok(),
+        };
+    }
+
+    fn set_idle(&mut self, xfer: ControlOut<B>) {
+        let [duration, report_id] = xfer.request().value.to_be_bytes();
+        match self.idle.set(self.device.report_ids(), report_id, duration) {
+            Ok(()) => xfer.accept().ok(),
+            Err(()) => xfer.reject().ok(),
+        };
+    }
+
+    fn set_protocol(&mut self, xfer: ControlOut<B>) {
+        // only boot interfaces are required to support the boot protocol
+        if self.device.subclass() != Subclass::BootInterface {
+            xfer.reject().ok();
+            return;
+        }
+        let req = xfer.request();
+        match ReportProtocol::new(req.value as u8) {
+            Some(protocol) => {
+                self.protocol = protocol;
+                self.device.set_protocol(protocol);
+                xfer.accept().ok();
+            }
+            None => {
+                xfer.reject().ok();
+            }
+        }
+    }
 }
 
 impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
@@ -152,6 +322,9 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
 
     fn reset(&mut self) {
         self.expect_interrupt_in_complete = false;
+        self.protocol = ReportProtocol::Report;
+        self.device.set_protocol(self.protocol);
+        self.idle = IdleTimers::default();
     }
 
     fn get_configuration_descriptors(
@@ -167,7 +340,7 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
 
         let report_descriptor = self.device.report_descriptor();
         let descriptor_len = report_descriptor.len();
-        if descriptor_len > u16::max_value() as usize {
+        if descriptor_len > u16::MAX as usize {
             return Err(UsbError::InvalidState);
         }
         let descriptor_len = (descriptor_len as u16).to_le_bytes();
@@ -205,19 +378,24 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
         match (req.request_type, req.recipient) {
-            (RequestType::Standard, Recipient::Interface) => {
-                if req.request == control::Request::GET_DESCRIPTOR {
-                    let (dtype, index) = req.descriptor_type_index();
-                    if dtype == DescriptorType::Report as u8 && index == 0 {
-                        let descriptor = self.device.report_descriptor();
-                        xfer.accept_with(descriptor).ok();
-                    }
+            (RequestType::Standard, Recipient::Interface)
+                if req.request == control::Request::GET_DESCRIPTOR =>
+            {
+                let (dtype, index) = req.descriptor_type_index();
+                if dtype == DescriptorType::Report as u8 && index == 0 {
+                    let descriptor = self.device.report_descriptor();
+                    xfer.accept_with(descriptor).ok();
                 }
             }
             (RequestType::Class, Recipient::Interface) => {
                 if let Some(request) = Request::new(req.request) {
-                    if request == Request::GetReport {
-                        self.get_report(xfer);
+                    match request {
+                        Request::GetReport => self.get_report(xfer),
+                        Request::GetIdle => self.get_idle(xfer),
+                        Request::GetProtocol => {
+                            xfer.accept_with(&[self.protocol as u8]).ok();
+                        }
+                        _ => (),
                     }
                 }
             }
@@ -231,6 +409,8 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
             if let Some(request) = Request::new(req.request) {
                 match request {
                     Request::SetReport => self.set_report(xfer),
+                    Request::SetIdle => self.set_idle(xfer),
+                    Request::SetProtocol => self.set_protocol(xfer),
                     _ => (),
                 }
             }
@@ -238,35 +418,285 @@ impl<B: UsbBus, D: HidDevice> UsbClass<B> for HidClass<'_, B, D> {
     }
 }
 
-#[derive(Default, Clone, PartialEq, Copy)]
-pub struct KbHidReport([u8; 8]);
+/// Length of the boot protocol keyboard report.
+pub const BOOT_REPORT_LEN: usize = 8;
+/// Number of key usages (0x00..=0xDF, all but the modifiers) covered by the NKRO bitmap.
+pub const NKRO_KEY_COUNT: usize = 224;
+/// Length of the report protocol keyboard report: the boot report
+/// followed by one bit per key usage.
+pub const NKRO_REPORT_LEN: usize = BOOT_REPORT_LEN + NKRO_KEY_COUNT / 8;
+
+/// A keyboard report that is kept in both encodings at once.
+///
+/// The first 8 bytes are the regular 6KRO boot report (modifiers, reserved,
+/// six keycodes), the remainder is a bitmap with one bit per key usage.
+/// The report descriptor declares the six keycode bytes as padding,
+/// so hosts in report protocol only look at the modifiers and the bitmap,
+/// while hosts in boot protocol get just the first 8 bytes.
+#[derive(Clone, PartialEq, Copy)]
+pub struct KbHidReport([u8; NKRO_REPORT_LEN]);
+
+impl Default for KbHidReport {
+    fn default() -> KbHidReport {
+        KbHidReport([0; NKRO_REPORT_LEN])
+    }
+}
 
 impl KbHidReport {
+    /// the 8 byte boot protocol report
     pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..BOOT_REPORT_LEN]
+    }
+
+    /// the full report protocol (n-key rollover) report
+    pub fn as_nkro_bytes(&self) -> &[u8] {
         &self.0
     }
+
     pub fn pressed(&mut self, kc: KeyCode) {
         use KeyCode::*;
         match kc {
             No => (),
             ErrorRollOver | PostFail | ErrorUndefined => self.set_all(kc),
             kc if kc.is_modifier() => self.0[0] |= kc.as_modifier_bit(),
-            _ => self.0[2..]
-                .iter_mut()
-                .find(|c| **c == 0)
-                .map(|c| *c = kc as u8)
-                .unwrap_or_else(|| self.set_all(ErrorRollOver)),
+            _ => {
+                self.set_bit(kc as u8);
+                self.0[2..BOOT_REPORT_LEN]
+                    .iter_mut()
+                    .find(|c| **c == 0 || **c == kc as u8)
+                    .map(|c| *c = kc as u8)
+                    .unwrap_or_else(|| self.set_all(ErrorRollOver))
+            }
+        }
+    }
+
+    fn set_bit(&mut self, code: u8) {
+        let code = code as usize;
+        if code < NKRO_KEY_COUNT {
+            self.0[BOOT_REPORT_LEN + code / 8] |= 1 << (code % 8);
         }
     }
+
     fn set_all(&mut self, kc: KeyCode) {
-        for c in &mut self.0[2..] {
+        for c in &mut self.0[2..BOOT_REPORT_LEN] {
             *c = kc as u8;
         }
     }
 
     pub fn clear(&mut self) {
-        for c in &mut self.0 {
+        for c in &mut self.0[..] {
             *c = 0;
         }
     }
 }
+
+/// Report ID of the consumer control report on the extra keys interface
+pub const REPORT_ID_CONSUMER: u8 = 0x01;
+/// Report ID of the system control report on the extra keys interface
+pub const REPORT_ID_SYSTEM: u8 = 0x02;
+
+/// A few commonly used usages from the consumer page (0x0C).
+/// Any other usage up to 0x3FF can be sent as a raw u16.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u16)]
+pub enum ConsumerUsage {
+    BrightnessUp = 0x006F,
+    BrightnessDown = 0x0070,
+    ScanNextTrack = 0x00B5,
+    ScanPreviousTrack = 0x00B6,
+    Stop = 0x00B7,
+    Eject = 0x00B8,
+    PlayPause = 0x00CD,
+    Mute = 0x00E2,
+    VolumeUp = 0x00E9,
+    VolumeDown = 0x00EA,
+    Mail = 0x018A,
+    Calculator = 0x0192,
+    LocalBrowser = 0x0194,
+    WwwSearch = 0x0221,
+    WwwHome = 0x0223,
+}
+
+/// Consumer control report: report ID, then a single
+/// little endian consumer page usage. Usage 0 means nothing is pressed.
+#[derive(Clone, PartialEq, Copy)]
+pub struct ConsumerReport([u8; 3]);
+
+impl ConsumerReport {
+    pub fn new(usage: u16) -> ConsumerReport {
+        let usage = usage.to_le_bytes();
+        ConsumerReport([REPORT_ID_CONSUMER, usage[0], usage[1]])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Default for ConsumerReport {
+    fn default() -> ConsumerReport {
+        ConsumerReport::new(0)
+    }
+}
+
+/// The system control usages from the generic desktop page (0x01)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum SystemUsage {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+/// System control report: report ID, then a single generic desktop usage.
+/// Usage 0 means nothing is pressed.
+#[derive(Clone, PartialEq, Copy)]
+pub struct SystemReport([u8; 2]);
+
+impl SystemReport {
+    pub fn new(usage: u8) -> SystemReport {
+        SystemReport([REPORT_ID_SYSTEM, usage])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Default for SystemReport {
+    fn default() -> SystemReport {
+        SystemReport::new(0)
+    }
+}
+
+/// A report on the extra keys interface, see extra_keys
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExtraReport {
+    Consumer(ConsumerReport),
+    System(SystemReport),
+}
+
+impl ExtraReport {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            ExtraReport::Consumer(report) => report.as_bytes(),
+            ExtraReport::System(report) => report.as_bytes(),
+        }
+    }
+}
+
+/// Length of the boot protocol mouse report (buttons, x, y).
+pub const MOUSE_BOOT_REPORT_LEN: usize = 3;
+
+/// Mouse report: buttons, x, y, wheel, horizontal pan.
+/// The first three bytes are the boot protocol mouse report.
+#[derive(Default, Clone, PartialEq, Copy)]
+pub struct MouseReport([u8; 5]);
+
+impl MouseReport {
+    pub fn new(buttons: u8, x: i8, y: i8, wheel: i8, pan: i8) -> MouseReport {
+        MouseReport([buttons, x as u8, y as u8, wheel as u8, pan as u8])
+    }
+
+    pub fn x(&self) -> i8 {
+        self.0[1] as i8
+    }
+
+    pub fn y(&self) -> i8 {
+        self.0[2] as i8
+    }
+
+    pub fn wheel(&self) -> i8 {
+        self.0[3] as i8
+    }
+
+    pub fn pan(&self) -> i8 {
+        self.0[4] as i8
+    }
+
+    /// the 3 byte boot protocol report
+    pub fn as_boot_bytes(&self) -> &[u8] {
+        &self.0[..MOUSE_BOOT_REPORT_LEN]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use no_std_compat::prelude::v1::*;
+
+    const IDS: &[u8] = &[REPORT_ID_CONSUMER, REPORT_ID_SYSTEM];
+
+    #[test]
+    fn idle_rate_per_report_id() {
+        let mut idle = IdleTimers::default();
+        assert_eq!(idle.set(IDS, REPORT_ID_CONSUMER, 2), Ok(()));
+        assert_eq!(idle.set(IDS, REPORT_ID_SYSTEM, 5), Ok(()));
+        assert_eq!(idle.get(IDS, REPORT_ID_CONSUMER), Some(2));
+        assert_eq!(idle.get(IDS, REPORT_ID_SYSTEM), Some(5));
+        // undeclared IDs are rejected, and don't change anything
+        assert_eq!(idle.set(IDS, 3, 1), Err(()));
+        assert_eq!(idle.get(IDS, 3), None);
+        assert_eq!(idle.get(IDS, 0), None);
+        // 0 sets every ID
+        assert_eq!(idle.set(IDS, 0, 7), Ok(()));
+        assert_eq!(idle.get(IDS, REPORT_ID_CONSUMER), Some(7));
+        assert_eq!(idle.get(IDS, REPORT_ID_SYSTEM), Some(7));
+    }
+
+    #[test]
+    fn idle_periods_are_counted_per_report_id() {
+        let mut idle = IdleTimers::default();
+        idle.set(IDS, REPORT_ID_CONSUMER, 1).unwrap();
+        idle.set(IDS, REPORT_ID_SYSTEM, 2).unwrap();
+        let due: Vec<Option<u8>> = (0..4).map(|_| idle.tick(IDS)).collect();
+        assert_eq!(due, [None, None, None, Some(REPORT_ID_CONSUMER)]);
+        idle.written(IDS, REPORT_ID_CONSUMER);
+        // the system report's period kept running meanwhile,
+        // both are due after 8 ms - one at a time, until written
+        let due: Vec<Option<u8>> = (0..4).map(|_| idle.tick(IDS)).collect();
+        assert_eq!(due, [None, None, None, Some(REPORT_ID_CONSUMER)]);
+        idle.written(IDS, REPORT_ID_CONSUMER);
+        assert_eq!(idle.tick(IDS), Some(REPORT_ID_SYSTEM));
+        idle.written(IDS, REPORT_ID_SYSTEM);
+        assert_eq!(idle.tick(IDS), None);
+    }
+
+    #[test]
+    fn nkro_bitmap_covers_usages_above_0x7f() {
+        let mut report = KbHidReport::default();
+        report.pressed(KeyCode::A);
+        report.pressed(KeyCode::Hangeul); // LANG1
+        report.pressed(KeyCode::KpRightParen); // 0xB7
+        report.pressed(KeyCode::LShift);
+        let bytes = report.as_nkro_bytes();
+        assert_eq!(bytes.len(), NKRO_REPORT_LEN);
+        assert_eq!(bytes[0], 0x02);
+        let bit_set = |code: usize| bytes[BOOT_REPORT_LEN + code / 8] & (1 << (code % 8)) != 0;
+        let set: Vec<usize> = (0..NKRO_KEY_COUNT).filter(|c| bit_set(*c)).collect();
+        let lang1 = KeyCode::Hangeul.to_u8();
+        assert!(lang1 >= 0x80);
+        assert_eq!(set, [0x04, lang1 as usize, 0xB7]);
+        // the boot report has them in its key slots
+        assert_eq!(report.as_bytes(), &[0x02, 0, 0x04, lang1, 0xB7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn no_report_ids_means_id_0() {
+        let mut idle = IdleTimers::default();
+        assert_eq!(idle.set(&[0], 0, 1), Ok(()));
+        assert_eq!(idle.set(&[0], 1, 1), Err(()));
+        assert_eq!(idle.get(&[0], 0), Some(1));
+        assert_eq!(idle.tick(&[0]), None);
+        assert_eq!(idle.tick(&[0]), None);
+        assert_eq!(idle.tick(&[0]), None);
+        assert_eq!(idle.tick(&[0]), Some(0));
+        // rate 0 never repeats
+        idle.set(&[0], 0, 0).unwrap();
+        assert!((0..2000).all(|_| idle.tick(&[0]).is_none()));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::ring_buffer::RingBuffer;
+use crate::usbout::StringSender;
+use crate::{RawHidClass, UsbSerial};
+
+/// The USB interfaces for host tools: the CDC-ACM serial port
+/// (debug output and console) and the raw HID interface.
+pub struct HostLink {
+    pub raw_class: RawHidClass,
+    pub serial: UsbSerial,
+    /// debug output waiting for the USB serial port
+    debug_buffer: RingBuffer,
+    /// bytes received on the USB serial port
+    pub serial_input: RingBuffer,
+}
+
+impl HostLink {
+    pub fn new(raw_class: RawHidClass, serial: UsbSerial) -> HostLink {
+        HostLink {
+            raw_class,
+            serial,
+            debug_buffer: RingBuffer::new(),
+            serial_input: RingBuffer::new(),
+        }
+    }
+
+    /// called from the USB interrupts - moves data between
+    /// the USB serial port and the ring buffers
+    pub fn poll_serial(&mut self) {
+        let mut buf = [0u8; 64];
+        if let Ok(count) = self.serial.read(&mut buf) {
+            self.serial_input.push(&buf[..count]);
+        }
+        self.flush_serial();
+    }
+
+    fn flush_serial(&mut self) {
+        while !self.debug_buffer.is_empty() {
+            match self.serial.write(self.debug_buffer.peek()) {
+                Ok(count) if count > 0 => self.debug_buffer.consume(count),
+                _ => break,
+            }
+        }
+    }
+}
+
+/// debug output goes to the USB serial port. Never blocks -
+/// if the host isn't reading, output is dropped once the buffer is full.
+impl StringSender for HostLink {
+    fn writeln(&mut self, s: &str) {
+        self.debug_buffer.push(s.as_bytes());
+        self.debug_buffer.push(b"\r\n");
+        self.flush_serial();
+    }
+}
@@ -0,0 +1,419 @@
+use crate::debounce::{Debounce, DebounceResult};
+use keytokey::{Keyboard, USBKeyOut};
+use no_std_compat::collections::VecDeque;
+use no_std_compat::prelude::v1::*;
+
+/// Ghost detection works on at most this many sources and sinks
+pub const MAX_GHOST_LINES: usize = 32;
+
+/// Which way the diodes point, anode to cathode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiodeDirection {
+    /// cathode on the source side - QMK's COL2ROW if the sources are rows
+    SinkToSource,
+    /// cathode on the sink side - QMK's ROW2COL if the sources are rows
+    SourceToSink,
+}
+
+/// The level of a driven line / a pressed key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActiveLevel {
+    /// driven low, inputs pulled up
+    Low,
+    /// driven high, inputs pulled down
+    High,
+}
+
+/// Per board matrix settings, from the board's layout file
+#[derive(Clone, Copy, Debug)]
+pub struct MatrixConfig {
+    /// suppress keys that might be ghosts, for matrices without (full) diodes
+    pub ghost_detection: bool,
+    pub diodes: DiodeDirection,
+    pub active: ActiveLevel,
+}
+
+impl MatrixConfig {
+    /// Whether the sinks have to be driven (and the sources read).
+    ///
+    /// Current has to flow through the diodes: active low drives the
+    /// cathode side low, active high drives the anode side high.
+    pub fn drives_sinks(&self) -> bool {
+        match (self.diodes, self.active) {
+            (DiodeDirection::SinkToSource, ActiveLevel::Low) => false,
+            (DiodeDirection::SourceToSink, ActiveLevel::Low) => true,
+            (DiodeDirection::SinkToSource, ActiveLevel::High) => true,
+            (DiodeDirection::SourceToSink, ActiveLevel::High) => false,
+        }
+    }
+}
+
+impl Default for MatrixConfig {
+    /// the original wiring: sources driven low, sinks with pull ups
+    fn default() -> MatrixConfig {
+        MatrixConfig {
+            ghost_detection: false,
+            diodes: DiodeDirection::SinkToSource,
+            active: ActiveLevel::Low,
+        }
+    }
+}
+
+/// The state of all N keys of a matrix (true = pressed), without the heap.
+/// N is the board's KEY_COUNT.
+#[derive(Clone, Copy, PartialEq)]
+pub struct KeyStates<const N: usize> {
+    keys: [bool; N],
+}
+
+impl<const N: usize> KeyStates<N> {
+    pub const fn new() -> KeyStates<N> {
+        KeyStates { keys: [false; N] }
+    }
+
+    pub fn len(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        N == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<bool> {
+        self.keys.get(index).copied()
+    }
+
+    /// indices beyond N are ignored
+    pub fn set(&mut self, index: usize, pressed: bool) {
+        if let Some(key) = self.keys.get_mut(index) {
+            *key = pressed;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        self.keys.iter().copied()
+    }
+}
+
+/// A keyboard matrix - something that can be scanned into a bit per key.
+///
+/// Bits are in source major order: index = source * sink_count + sink,
+/// which is also the index into the translation table / keymap.
+/// N is the number of keys, source_count * sink_count.
+pub trait KeyMatrix<const N: usize> {
+    /// scan all keys, returns the state (true = pressed)
+    fn scan(&mut self) -> &KeyStates<N>;
+
+    /// Advance a non blocking scan by one step, returns true
+    /// once a complete scan is available from output().
+    /// The default does a complete scan on every call.
+    fn scan_step(&mut self) -> bool {
+        self.scan();
+        true
+    }
+
+    /// the result of the last scan
+    fn output(&self) -> &KeyStates<N>;
+
+    fn source_count(&self) -> usize;
+
+    fn sink_count(&self) -> usize;
+
+    /// number of keys (matrix positions)
+    fn len(&self) -> usize {
+        self.source_count() * self.sink_count()
+    }
+
+    /// matrix index of a source/sink crossing
+    fn index(&self, source: usize, sink: usize) -> usize {
+        source * self.sink_count() + sink
+    }
+
+    /// (source, sink) of a matrix index
+    fn position(&self, index: usize) -> (usize, usize) {
+        (index / self.sink_count(), index % self.sink_count())
+    }
+}
+
+/// Ghost detection for matrices without (full) diodes.
+///
+/// When two sources share two pressed sinks - a rectangle in the matrix -
+/// any one of the four keys might be a phantom of the other three.
+/// Keys that are part of a rectangle keep their state from `previous`
+/// (the last scan), so keys already held stay held and new ones are ignored
+/// until the pattern is resolved. Returns whether anything was suppressed.
+/// At most MAX_GHOST_LINES sources and sinks are supported.
+pub fn suppress_ghosts<const N: usize>(
+    states: &mut KeyStates<N>,
+    previous: &KeyStates<N>,
+    source_count: usize,
+    sink_count: usize,
+) -> bool {
+    let source_count = source_count.min(MAX_GHOST_LINES);
+    let mut rows = [0u32; MAX_GHOST_LINES];
+    for (source, row) in rows.iter_mut().enumerate().take(source_count) {
+        *row = (0..sink_count.min(MAX_GHOST_LINES)).fold(0, |mask, sink| {
+            if states.get(source * sink_count + sink).unwrap_or(false) {
+                mask | (1 << sink)
+            } else {
+                mask
+            }
+        });
+    }
+    let rows = &rows[..source_count];
+    let mut ambiguous = [0u32; MAX_GHOST_LINES];
+    for (a, row_a) in rows.iter().enumerate() {
+        for (b, row_b) in rows.iter().enumerate().skip(a + 1) {
+            let shared = row_a & row_b;
+            if shared.count_ones() >= 2 {
+                ambiguous[a] |= shared;
+                ambiguous[b] |= shared;
+            }
+        }
+    }
+    let mut suppressed = false;
+    for (source, mask) in ambiguous.iter().enumerate().take(source_count) {
+        for sink in (0..sink_count.min(MAX_GHOST_LINES)).filter(|sink| mask & (1 << sink) != 0) {
+            let ii = source * sink_count + sink;
+            let before = previous.get(ii).unwrap_or(false);
+            if states.get(ii) != Some(before) {
+                states.set(ii, before);
+                suppressed = true;
+            }
+        }
+    }
+    suppressed
+}
+
+/// In memory matrix that plays back a script of scans,
+/// for running the scan -> debounce -> keytokey pipeline off target.
+///
+/// Once the script is exhausted the last scan is repeated,
+/// so held keys stay held. The script itself lives on the heap,
+/// this is meant for the host.
+pub struct ScriptedMatrix<const N: usize> {
+    source_count: usize,
+    sink_count: usize,
+    script: VecDeque<Vec<usize>>,
+    output: KeyStates<N>,
+}
+
+impl<const N: usize> ScriptedMatrix<N> {
+    pub fn new(source_count: usize, sink_count: usize) -> ScriptedMatrix<N> {
+        assert!(source_count * sink_count == N);
+        ScriptedMatrix {
+            source_count,
+            sink_count,
+            script: VecDeque::new(),
+            output: KeyStates::new(),
+        }
+    }
+
+    /// queue one scan in which exactly the given matrix indices are pressed
+    pub fn push_scan(&mut self, pressed: &[usize]) {
+        self.script.push_back(pressed.to_vec());
+    }
+
+    /// queue `count` identical scans - debouncing needs a few to settle
+    pub fn hold(&mut self, pressed: &[usize], count: usize) {
+        for _ in 0..count {
+            self.push_scan(pressed);
+        }
+    }
+
+    /// number of queued scans not yet played back
+    pub fn remaining(&self) -> usize {
+        self.script.len()
+    }
+}
+
+impl<const N: usize> KeyMatrix<N> for ScriptedMatrix<N> {
+    fn scan(&mut self) -> &KeyStates<N> {
+        if let Some(pressed) = self.script.pop_front() {
+            self.output = KeyStates::new();
+            for ii in pressed {
+                self.output.set(ii, true);
+            }
+        }
+        &self.output
+    }
+
+    fn output(&self) -> &KeyStates<N> {
+        &self.output
+    }
+
+    fn source_count(&self) -> usize {
+        self.source_count
+    }
+
+    fn sink_count(&self) -> usize {
+        self.sink_count
+    }
+}
+
+/// Feed one scan through the debouncer into keytokey.
+///
+/// keymap translates matrix indices to keycodes (positions beyond
+/// the keymap report their index), delta is the time in ms since the last change.
+/// Returns whether any key changed state.
+pub fn process_scan<'a, T: USBKeyOut, D: Debounce, const N: usize>(
+    states: &KeyStates<N>,
+    debouncer: &mut D,
+    keymap: &[u32],
+    k2k: &mut Keyboard<'a, T>,
+    delta: u16,
+) -> bool {
+    let mut changed = false;
+    for (ii, pressed) in states.iter().enumerate() {
+        let keycode = *keymap.get(ii).unwrap_or(&(ii as u32));
+        match debouncer.update(ii, pressed) {
+            DebounceResult::NoChange => continue,
+            DebounceResult::Pressed => k2k.add_keypress(keycode, delta),
+            DebounceResult::Released => k2k.add_keyrelease(keycode, delta),
+        }
+        changed = true;
+        k2k.handle_keys().ok();
+        k2k.clear_unhandled();
+    }
+    if !changed {
+        k2k.add_timeout(delta);
+        k2k.handle_keys().ok();
+        k2k.clear_unhandled();
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::{DeferredDebouncer, Thresholds};
+    use keytokey::handlers::USBKeyboard;
+    use keytokey::test_helpers::KeyOutCatcher;
+    use keytokey::KeyCode;
+
+    fn states<const N: usize>(pressed: &[usize]) -> KeyStates<N> {
+        let mut states = KeyStates::new();
+        for ii in pressed {
+            states.set(*ii, true);
+        }
+        states
+    }
+
+    fn pressed<const N: usize>(states: &KeyStates<N>) -> Vec<usize> {
+        states.iter().enumerate().filter(|(_, p)| *p).map(|(ii, _)| ii).collect()
+    }
+
+    // 3 sources x 3 sinks:
+    // 0 1 2
+    // 3 4 5
+    // 6 7 8
+
+    #[test]
+    fn phantom_fourth_key_is_suppressed() {
+        // 0, 1 and 3 held one after the other, then the phantom 4 shows up
+        let previous = states::<9>(&[0, 1, 3]);
+        let mut scan = states::<9>(&[0, 1, 3, 4]);
+        assert!(suppress_ghosts(&mut scan, &previous, 3, 3));
+        assert_eq!(pressed(&scan), [0, 1, 3]);
+    }
+
+    #[test]
+    fn real_rectangle_is_ambiguous() {
+        // four real presses in one scan can't be told from a ghost
+        let previous = states::<9>(&[]);
+        let mut scan = states::<9>(&[4, 5, 7, 8]);
+        assert!(suppress_ghosts(&mut scan, &previous, 3, 3));
+        assert!(pressed(&scan).is_empty());
+    }
+
+    #[test]
+    fn rectangle_keeps_the_previous_state() {
+        // 2 was held before the rectangle appeared, 8 outside of it is unaffected
+        let previous = states::<9>(&[2]);
+        let mut scan = states::<9>(&[1, 2, 4, 5, 8]);
+        assert!(suppress_ghosts(&mut scan, &previous, 3, 3));
+        assert_eq!(pressed(&scan), [2, 8]);
+
+        // released keys in a rectangle stay pressed until it resolves
+        let previous = states::<9>(&[0, 1, 3, 4]);
+        let mut scan = states::<9>(&[0, 1, 3, 4, 6, 7]);
+        assert!(suppress_ghosts(&mut scan, &previous, 3, 3));
+        assert_eq!(pressed(&scan), [0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn one_shared_sink_is_no_ghost() {
+        let previous = states::<9>(&[]);
+        for pattern in &[&[0, 1, 3][..], &[0, 3, 6], &[0, 1, 2, 3], &[0, 4, 8]] {
+            let mut scan = states::<9>(pattern);
+            assert!(!suppress_ghosts(&mut scan, &previous, 3, 3));
+            assert_eq!(pressed(&scan), *pattern);
+        }
+    }
+
+    #[test]
+    fn lines_beyond_the_limit_are_not_checked() {
+        const SINKS: usize = MAX_GHOST_LINES + 8;
+        let previous = states::<{ 2 * SINKS }>(&[]);
+        // a rectangle on sinks 33 and 34 is out of reach
+        let beyond = [33, 34, SINKS + 33, SINKS + 34];
+        let mut scan = states::<{ 2 * SINKS }>(&beyond);
+        assert!(!suppress_ghosts(&mut scan, &previous, 2, SINKS));
+        assert_eq!(pressed(&scan), beyond);
+        // one on sinks 30 and 31 is still caught
+        let mut scan = states::<{ 2 * SINKS }>(&[30, 31, SINKS + 30, SINKS + 31]);
+        assert!(suppress_ghosts(&mut scan, &previous, 2, SINKS));
+        assert!(pressed(&scan).is_empty());
+    }
+
+    /// play back every queued scan, returning which ones changed a key
+    fn play<const N: usize>(
+        matrix: &mut ScriptedMatrix<N>,
+        debouncer: &mut DeferredDebouncer<N>,
+        keymap: &[u32],
+        k2k: &mut Keyboard<KeyOutCatcher>,
+    ) -> Vec<bool> {
+        let mut changes = Vec::new();
+        while matrix.remaining() > 0 {
+            let states = *matrix.scan();
+            changes.push(process_scan(&states, debouncer, keymap, k2k, 1));
+        }
+        changes
+    }
+
+    #[test]
+    fn scripted_scans_press_and_release_keys() {
+        let mut matrix = ScriptedMatrix::<4>::new(2, 2);
+        let thresholds = Thresholds {
+            press: 2,
+            release: 2,
+        };
+        let mut debouncer = DeferredDebouncer::<4>::new(4, thresholds, &[]);
+        let keymap = [
+            KeyCode::A.to_u32(),
+            KeyCode::B.to_u32(),
+            KeyCode::C.to_u32(),
+            KeyCode::D.to_u32(),
+        ];
+        let mut k2k = Keyboard::new(KeyOutCatcher::new());
+        k2k.add_handler(Box::new(USBKeyboard::new()));
+
+        // a bouncing press of B, then D joins in
+        matrix.push_scan(&[1]);
+        matrix.push_scan(&[]);
+        matrix.hold(&[1], 2);
+        matrix.hold(&[1, 3], 2);
+        let changes = play(&mut matrix, &mut debouncer, &keymap, &mut k2k);
+        assert_eq!(changes, [false, false, false, true, false, true]);
+        let mut report = k2k.output.reports.last().unwrap().clone();
+        report.sort();
+        assert_eq!(report, [KeyCode::B.to_u8(), KeyCode::D.to_u8()]);
+
+        // release both
+        k2k.output.clear();
+        matrix.hold(&[], 2);
+        let changes = play(&mut matrix, &mut debouncer, &keymap, &mut k2k);
+        assert_eq!(changes, [false, true]);
+        assert!(k2k.output.reports.last().unwrap().is_empty());
+    }
+}
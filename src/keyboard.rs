@@ -1,20 +1,59 @@
-use crate::hid::{HidDevice, Protocol, ReportType, Subclass};
+use crate::hid::{
+    HidClass, HidDevice, KbHidReport, Protocol, ReportProtocol, ReportType, Subclass,
+    NKRO_REPORT_LEN,
+};
+use crate::report_sink::{KeyboardSink, ReportSink};
+use usb_device::bus::UsbBus;
 //use stm32f1xx_hal::prelude::*;
 
+// Report protocol layout, see hid::KbHidReport:
+// modifiers, reserved byte, 6 bytes of padding (the boot protocol key array),
+// then a 224 bit bitmap for the key usages 0x00..=0xDF.
 const REPORT_DESCRIPTOR: &[u8] = &[
     0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
     0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x03, 0x95, 0x05, 0x75, 0x01,
     0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x03, 0x95, 0x06,
-    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0x09, 0x03,
-    0x75, 0x08, 0x95, 0x40, 0xB1, 0x02, 0xC0,
+    0x75, 0x08, 0x81, 0x03, 0x05, 0x07, 0x19, 0x00, 0x29, 0xDF, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01,
+    0x95, 0xE0, 0x81, 0x02, 0x09, 0x03, 0x75, 0x08, 0x95, 0x40, 0xB1, 0x02, 0xC0,
 ];
 
+/// The LEDs in the keyboard output report, as bit masks
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum HostLed {
+    NumLock = 0x01,
+    CapsLock = 0x02,
+    ScrollLock = 0x04,
+    Compose = 0x08,
+    Kana = 0x10,
+}
+
+/// LED state as last sent by the host
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct KeyboardLeds(pub u8);
+
+impl KeyboardLeds {
+    pub fn is_on(&self, led: HostLed) -> bool {
+        self.0 & (led as u8) != 0
+    }
+}
+
 pub struct Keyboard {
-    report: [u8; 8],
+    report: KbHidReport,
+    protocol: ReportProtocol,
+    leds: KeyboardLeds,
 }
 impl Keyboard {
     pub fn new() -> Keyboard {
-        Keyboard { report: [0; 8] }
+        Keyboard {
+            report: KbHidReport::default(),
+            protocol: ReportProtocol::Report,
+            leds: KeyboardLeds::default(),
+        }
+    }
+
+    pub fn leds(&self) -> KeyboardLeds {
+        self.leds
     }
 }
 
@@ -33,7 +72,10 @@ impl HidDevice for Keyboard {
 
     fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], ()> {
         match report_type {
-            ReportType::Input => Ok(&self.report),
+            ReportType::Input => match self.protocol {
+                ReportProtocol::Boot => Ok(self.report.as_bytes()),
+                ReportProtocol::Report => Ok(self.report.as_nkro_bytes()),
+            },
             _ => Err(()),
         }
     }
@@ -45,8 +87,45 @@ impl HidDevice for Keyboard {
         data: &[u8],
     ) -> Result<(), ()> {
         if report_type == ReportType::Output && report_id == 0 && data.len() == 1 {
+            self.leds = KeyboardLeds(data[0]);
             return Ok(());
         }
         Err(())
     }
+
+    fn set_protocol(&mut self, protocol: ReportProtocol) {
+        self.protocol = protocol;
+    }
+
+    /// the NKRO report is larger than the default, and has to fit into one packet
+    fn max_packet_size(&self) -> u16 {
+        NKRO_REPORT_LEN as u16
+    }
+}
+
+/// the keyboard endpoint, reports are written in the encoding matching the host's protocol
+/// and kept for GET_REPORT once the endpoint accepted them
+impl<'a, B: UsbBus> ReportSink for HidClass<'a, B, Keyboard> {
+    fn write_report(&mut self, report: &KbHidReport) -> Result<usize, ()> {
+        let written = match self.protocol() {
+            ReportProtocol::Boot => self.write(report.as_bytes()),
+            ReportProtocol::Report => self.write(report.as_nkro_bytes()),
+        };
+        if let Ok(count) = written {
+            if count > 0 {
+                self.device_mut().report = *report;
+            }
+        }
+        written
+    }
+}
+
+impl<'a, B: UsbBus> KeyboardSink for HidClass<'a, B, Keyboard> {
+    fn host_leds(&self) -> KeyboardLeds {
+        self.device().leds()
+    }
+
+    fn tick_idle(&mut self) -> bool {
+        HidClass::tick_idle(self).is_some()
+    }
 }
@@ -0,0 +1,200 @@
+use crate::extended_handlers::{
+    consumer_keycode, mouse_keycode, system_keycode, ExtendedKeyOut, ExtraKeysHandler,
+    MouseKeysHandler,
+};
+use crate::hid::{ConsumerUsage, SystemUsage};
+use crate::mouse::MouseAction;
+use core::convert::TryFrom;
+use keytokey::{handlers, premade, HandlerID, KeyCode, Keyboard, Modifier, USBKeyOut};
+use no_std_compat::prelude::v1::*;
+
+/// matrix index -> keycode, generated by build.rs from the board's layout file
+pub const TRANSLATION: &[u32; crate::board::KEY_COUNT] = include!(concat!(env!("OUT_DIR"), "/translation.rs"));
+
+
+/// the keytokey handler setup - layers, tap dances, one shots...
+pub fn get_keytokey<'a, T: ExtendedKeyOut>(output: T) -> Keyboard<'a, T> {
+    //output.debug(&format!("A{}", ALLOCATOR.get()));
+    let mut k = Keyboard::new(output);
+    //k.output.debug(&format!("B{}", ALLOCATOR.get()));
+    //one shots must come before space cadets
+    //k.add_handler(premade::one_shot_shift(400, 1000));
+    //k.add_handler(premade::one_shot_ctrl(400, 1000));
+    //k.add_handler(premade::one_shot_alt(400, 1000));
+    //k.add_handler(premade::one_shot_gui(400, 1000));
+    //k.output.debug(&format!("B1{}", ALLOCATOR.get()));
+
+
+    use handlers::LayerAction::SendString;
+    use handlers::LayerAction::RewriteToShifted as RTS;
+    //k.add_handler(premade::space_cadet_handler(KeyCode::F, KeyCode::U, 
+        //k.future_handler_id(2)));
+  //  k.add_handler(premade::space_cadet_handler(KeyCode::J, KeyCode::H, 
+   //     k.future_handler_id(2)));
+    let umlaut_id = k.future_handler_id(2);
+
+    struct LayerToggleTapDance {handler_id: HandlerID, toggle: bool}
+    impl handlers::TapDanceAction for LayerToggleTapDance {
+        fn on_tapdance( &mut self, trigger: u32, 
+            output: &mut impl USBKeyOut, 
+                tap_count: u8, 
+                _tap_end: handlers::TapDanceEnd){
+                    match tap_count {
+                        0 => {},
+                        1 => output.send_keys(&[KeyCode::try_from(trigger).unwrap()]),
+                        _ => {
+                            if self.toggle  {
+                                output.state().toggle_handler(self.handler_id);
+                            }   
+                            else {
+                                output.state().enable_handler(self.handler_id);
+                            }
+                        },
+                    }
+         }
+    }
+
+    k.add_handler(
+        Box::new(handlers::TapDance::new(
+            KeyCode::F8,
+            LayerToggleTapDance{handler_id: umlaut_id, toggle: false},
+            100
+        )));
+
+
+
+    //the umlaut layer - mut come after the tap dance!
+    k.add_handler(Box::new(
+        handlers::Layer::new(vec![
+            (KeyCode::A, RTS(0xE4, 0xC4)),
+            (KeyCode::S, RTS(0xF6, 0xD6)),
+            (KeyCode::F, RTS(0xFC, 0xDC)),
+            (KeyCode::SColon, SendString("ß")),
+        ],
+        handlers::AutoOff::AfterNonModifier
+        )
+    )
+    );
+
+
+    const NUMPAD_MAP: &[(u32, u32)] = &[
+            (KeyCode::U.to_u32(), KeyCode::Kb7.to_u32()),
+            (KeyCode::I.to_u32(), KeyCode::Kb8.to_u32()),
+            (KeyCode::O.to_u32(), KeyCode::Kb9.to_u32()),
+            (KeyCode::J.to_u32(), KeyCode::Kb4.to_u32()),
+            (KeyCode::K.to_u32(), KeyCode::Kb5.to_u32()),
+            (KeyCode::L.to_u32(), KeyCode::Kb6.to_u32()),
+            (KeyCode::M.to_u32(), KeyCode::Kb1.to_u32()),
+            (KeyCode::Comma.to_u32(), KeyCode::Kb2.to_u32()),
+            (KeyCode::Dot.to_u32(), KeyCode::Kb3.to_u32()),
+            (KeyCode::N.to_u32(), KeyCode::Kb0.to_u32()),
+            (KeyCode::Space.to_u32(), KeyCode::Tab.to_u32()),
+            (KeyCode::BSlash.to_u32(), KeyCode::Dot.to_u32()),
+            (KeyCode::H.to_u32(), KeyCode::Comma.to_u32()),
+            (KeyCode::F5.to_u32(), system_keycode(SystemUsage::Sleep)),
+            (KeyCode::F9.to_u32(), consumer_keycode(ConsumerUsage::Mute)),
+            (KeyCode::F10.to_u32(), consumer_keycode(ConsumerUsage::VolumeDown)),
+            (KeyCode::F11.to_u32(), consumer_keycode(ConsumerUsage::VolumeUp)),
+            (KeyCode::F12.to_u32(), consumer_keycode(ConsumerUsage::PlayPause)),
+        ];
+    let numpad_id = k.future_handler_id(2);
+    k.add_handler(
+        Box::new(handlers::TapDance::new(
+            KeyCode::F6,
+            LayerToggleTapDance{handler_id: numpad_id, toggle: true},
+            100
+        )));
+    k.add_handler(Box::new(
+        handlers::RewriteLayer::new(NUMPAD_MAP)
+    )
+    );
+
+    const MOUSE_MAP: &[(u32, u32)] = &[
+            (KeyCode::E.to_u32(), mouse_keycode(MouseAction::Up)),
+            (KeyCode::D.to_u32(), mouse_keycode(MouseAction::Down)),
+            (KeyCode::S.to_u32(), mouse_keycode(MouseAction::Left)),
+            (KeyCode::F.to_u32(), mouse_keycode(MouseAction::Right)),
+            (KeyCode::R.to_u32(), mouse_keycode(MouseAction::WheelUp)),
+            (KeyCode::W.to_u32(), mouse_keycode(MouseAction::WheelDown)),
+            (KeyCode::Q.to_u32(), mouse_keycode(MouseAction::PanLeft)),
+            (KeyCode::T.to_u32(), mouse_keycode(MouseAction::PanRight)),
+            (KeyCode::J.to_u32(), mouse_keycode(MouseAction::Button1)),
+            (KeyCode::K.to_u32(), mouse_keycode(MouseAction::Button3)),
+            (KeyCode::L.to_u32(), mouse_keycode(MouseAction::Button2)),
+        ];
+    let mouse_id = k.future_handler_id(2);
+    k.add_handler(
+        Box::new(handlers::TapDance::new(
+            KeyCode::F7,
+            LayerToggleTapDance{handler_id: mouse_id, toggle: true},
+            100
+        )));
+    k.add_handler(Box::new(
+        handlers::RewriteLayer::new(MOUSE_MAP)
+    )
+    );
+
+
+
+
+    let dvorak_id = k.add_handler(premade::dvorak());
+
+    //k.output.debug(&format!("C{}", ALLOCATOR.get()));
+  //  k.output.state().enable_handler(umlaut_id);
+    let mut abort = premade::ActionAbort::new();
+    abort.set_abort_status(dvorak_id, true);
+    abort.set_abort_status(numpad_id, false);
+    abort.set_abort_status(mouse_id, false);
+    abort.set_abort_status(umlaut_id , false);
+        //k.output.debug(&format!("D{}", ALLOCATOR.get()));
+
+    k.add_handler(
+        Box::new(handlers::OneShot::new(
+            KeyCode::LShift,
+            KeyCode::RShift,
+            premade::ActionHandler::new(
+                Modifier::Shift as HandlerID,
+            ),
+            abort,
+            premade::ActionToggleHandler{id: umlaut_id},
+            400,
+            1000,
+        )));
+//$! -> 41, yeah.
+    k.add_handler(
+        Box::new(handlers::TapDance::new(
+            KeyCode::F1,
+            LayerToggleTapDance{handler_id: dvorak_id, toggle: true},
+            100
+        )));
+    
+    //k.output.debug(&format!("E{}", ALLOCATOR.get()));
+
+
+    k.output.state().enable_handler(dvorak_id);
+
+    //k.output.debug(&format!("F{}", ALLOCATOR.get()));
+    //k.add_handler(Box::new(premade::CopyPaste{}));
+    k.add_handler(Box::new(
+           handlers::PressMacro::new(KeyCode::Copy,
+                                        vec!(KeyCode::LGui, KeyCode::LCtrl, KeyCode::LShift, KeyCode::C))));
+            
+    k.add_handler(Box::new(
+           handlers::PressMacro::new(KeyCode::Paste,
+                                        vec!(KeyCode::LCtrl, KeyCode::V))));
+            
+
+    //k.output.debug(&format!("G{}", ALLOCATOR.get()));
+
+
+    //k.output.debug(&format!("I{}", ALLOCATOR.get()));
+    //media/system keycodes are beyond unicode, but keep them away from UnicodeKeyboard anyway
+    k.add_handler(Box::new(ExtraKeysHandler::new()));
+    k.add_handler(Box::new(MouseKeysHandler {}));
+    k.add_handler(Box::new(handlers::UnicodeKeyboard::new()));
+    k.add_handler(Box::new(handlers::USBKeyboard::new()));
+    //k.add_handler(Box::new(keytokey::debug_handlers::TranslationHelper {}));
+    //k.output.debug(&format!("J{}", ALLOCATOR.get()));
+ 
+    k
+}
@@ -0,0 +1,190 @@
+use core::ptr;
+use no_std_compat::prelude::v1::*;
+use stm32f1xx_hal::stm32;
+
+// The top 4 pages of flash, kept out of the FLASH region in memory.x.
+const STORE_START: u32 = 0x0800_F000;
+const PAGE_SIZE: u32 = 1024;
+const PAGE_COUNT: u32 = 4;
+
+const MAGIC: u32 = 0x4B32_4B32; // "K2K2", "K2KM" records had no layout hash
+/// magic, sequence number, layout hash, entry count
+const HEADER_WORDS: usize = 4;
+/// every record has to fit into one page, including the trailing crc
+pub const MAX_ENTRIES: usize = (PAGE_SIZE as usize / 4) - HEADER_WORDS - 1;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashError {
+    TooLarge,
+    ProgrammingError,
+    WriteProtected,
+    VerifyFailed,
+}
+
+// Each page holds at most one record:
+//   magic, sequence, layout hash, entry count, entries (matrix index -> keycode), crc32
+// The layout hash is the crc32 of the compiled in keymap, so a record saved
+// by firmware for another board or layout is ignored instead of misread.
+// Saving writes to the page after the newest valid one, so the pages
+// wear evenly and an interrupted write leaves the previous record intact.
+
+fn page_address(page: u32) -> u32 {
+    STORE_START + page * PAGE_SIZE
+}
+
+fn read_word(address: u32) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+fn crc32(words: impl Iterator<Item = u32>) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for word in words {
+        for byte in word.to_le_bytes().iter() {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (!(crc & 1)).wrapping_add(1);
+                crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+    !crc
+}
+
+/// identifies the compiled in keymap, see the record layout above
+fn layout_hash(default: &[u32]) -> u32 {
+    crc32(default.iter().cloned())
+}
+
+/// (sequence, layout hash, entry count) of the page's record, if it is intact
+fn valid_record(page: u32) -> Option<(u32, u32, usize)> {
+    let address = page_address(page);
+    if read_word(address) != MAGIC {
+        return None;
+    }
+    let sequence = read_word(address + 4);
+    let hash = read_word(address + 8);
+    let count = read_word(address + 12) as usize;
+    if count > MAX_ENTRIES {
+        return None;
+    }
+    let data_words = (HEADER_WORDS + count) as u32;
+    let crc = crc32((0..data_words).map(|ii| read_word(address + ii * 4)));
+    if crc != read_word(address + data_words * 4) {
+        return None;
+    }
+    Some((sequence, hash, count))
+}
+
+/// (page, sequence, layout hash, entry count) of the newest intact record
+fn newest_record() -> Option<(u32, u32, u32, usize)> {
+    (0..PAGE_COUNT)
+        .filter_map(|page| valid_record(page).map(|(seq, hash, count)| (page, seq, hash, count)))
+        .max_by_key(|(_page, seq, _hash, _count)| *seq)
+}
+
+/// The stored keymap, or a copy of default if nothing valid was stored
+/// or it was stored for a different layout.
+pub fn load<const N: usize>(default: &[u32; N]) -> [u32; N] {
+    let mut keymap = *default;
+    match newest_record() {
+        Some((page, _sequence, hash, count)) if hash == layout_hash(default) && count == N => {
+            let address = page_address(page) + (HEADER_WORDS as u32) * 4;
+            for (ii, entry) in keymap.iter_mut().enumerate() {
+                *entry = read_word(address + ii as u32 * 4);
+            }
+        }
+        _ => {}
+    }
+    keymap
+}
+
+/// Store the keymap, tagged with the layout of default.
+/// Blocks for the page erase, ~20ms, so don't call this while typing,
+/// nor while holding a lock that masks the USB interrupts.
+pub fn save(keymap: &[u32], default: &[u32]) -> Result<(), FlashError> {
+    if keymap.len() > MAX_ENTRIES {
+        return Err(FlashError::TooLarge);
+    }
+    let (page, sequence) = match newest_record() {
+        Some((page, sequence, _hash, _count)) => {
+            ((page + 1) % PAGE_COUNT, sequence.wrapping_add(1))
+        }
+        None => (0, 0),
+    };
+    let header = [MAGIC, sequence, layout_hash(default), keymap.len() as u32];
+    let data = || header.iter().chain(keymap.iter()).cloned();
+    let crc = crc32(data());
+
+    let flash = unsafe { &*stm32::FLASH::ptr() };
+    unlock(flash);
+    let result = erase_page(flash, page_address(page)).and_then(|_| {
+        let mut address = page_address(page);
+        for word in data().chain(core::iter::once(crc)) {
+            program_word(flash, address, word)?;
+            address += 4;
+        }
+        Ok(())
+    });
+    flash.cr.modify(|_, w| w.lock().set_bit());
+    result?;
+
+    match valid_record(page) {
+        Some((seq, _hash, _count)) if seq == sequence => Ok(()),
+        _ => Err(FlashError::VerifyFailed),
+    }
+}
+
+fn unlock(flash: &stm32::flash::RegisterBlock) {
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.key().bits(FLASH_KEY1) });
+        flash.keyr.write(|w| unsafe { w.key().bits(FLASH_KEY2) });
+    }
+}
+
+fn wait_and_check(flash: &stm32::flash::RegisterBlock) -> Result<(), FlashError> {
+    while flash.sr.read().bsy().bit_is_set() {}
+    let sr = flash.sr.read();
+    let result = if sr.wrprterr().bit_is_set() {
+        Err(FlashError::WriteProtected)
+    } else if sr.pgerr().bit_is_set() {
+        Err(FlashError::ProgrammingError)
+    } else {
+        Ok(())
+    };
+    // error and end of operation flags are cleared by writing 1
+    flash
+        .sr
+        .write(|w| w.eop().set_bit().pgerr().set_bit().wrprterr().set_bit());
+    result
+}
+
+fn erase_page(flash: &stm32::flash::RegisterBlock, address: u32) -> Result<(), FlashError> {
+    flash.cr.modify(|_, w| w.per().set_bit());
+    flash.ar.write(|w| unsafe { w.far().bits(address) });
+    flash.cr.modify(|_, w| w.strt().set_bit());
+    let result = wait_and_check(flash);
+    flash.cr.modify(|_, w| w.per().clear_bit());
+    result
+}
+
+/// the flash is programmed in half words
+fn program_word(
+    flash: &stm32::flash::RegisterBlock,
+    address: u32,
+    word: u32,
+) -> Result<(), FlashError> {
+    flash.cr.modify(|_, w| w.pg().set_bit());
+    let mut result = Ok(());
+    for (ii, half) in [word as u16, (word >> 16) as u16].iter().enumerate() {
+        unsafe { ptr::write_volatile((address + ii as u32 * 2) as *mut u16, *half) };
+        result = wait_and_check(flash);
+        if result.is_err() {
+            break;
+        }
+    }
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+    result
+}
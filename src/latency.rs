@@ -0,0 +1,62 @@
+/// Measurements longer than this are dropped - the edge probably
+/// didn't cause the report (bounce, tap dance timeouts...)
+const MAX_LATENCY_MS: u32 = 100;
+
+/// Latency from a matrix edge (the first scan that saw a change,
+/// before debouncing) to the keyboard report being written to the endpoint,
+/// in TIM4 ticks (ms).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Latency {
+    /// time of the edge still waiting for its report
+    pending: Option<u32>,
+    pub count: u32,
+    pub last_ms: u32,
+    pub min_ms: u32,
+    pub max_ms: u32,
+    total_ms: u32,
+}
+
+impl Latency {
+    pub const fn new() -> Latency {
+        Latency {
+            pending: None,
+            count: 0,
+            last_ms: 0,
+            min_ms: 0,
+            max_ms: 0,
+            total_ms: 0,
+        }
+    }
+
+    /// a scan saw the matrix change - starts a measurement
+    /// unless one is running (and not yet stale)
+    pub fn edge(&mut self, time_ms: u32) {
+        match self.pending {
+            Some(start) if time_ms.wrapping_sub(start) <= MAX_LATENCY_MS => {}
+            _ => self.pending = Some(time_ms),
+        }
+    }
+
+    /// a keyboard report was written - completes the running measurement
+    pub fn written(&mut self, time_ms: u32) {
+        if let Some(start) = self.pending.take() {
+            let latency = time_ms.wrapping_sub(start);
+            if latency > MAX_LATENCY_MS {
+                return;
+            }
+            if self.count == 0 || latency < self.min_ms {
+                self.min_ms = latency;
+            }
+            if latency > self.max_ms {
+                self.max_ms = latency;
+            }
+            self.last_ms = latency;
+            self.total_ms = self.total_ms.saturating_add(latency);
+            self.count += 1;
+        }
+    }
+
+    pub fn average_ms(&self) -> u32 {
+        self.total_ms.checked_div(self.count).unwrap_or(0)
+    }
+}
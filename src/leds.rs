@@ -0,0 +1,48 @@
+use crate::keyboard::{HostLed, KeyboardLeds};
+use embedded_hal::digital::v2::OutputPin;
+use stm32f1xx_hal::gpio::{gpioa::*, gpiob::*, Output, PushPull};
+
+/// Drives indicator LEDs from the host's keyboard LED state.
+///
+/// Each pin is paired with the host LED it displays,
+/// and is driven high while that LED is on.
+/// A and B are the number of LEDs on port A and B.
+pub struct Leds<const A: usize, const B: usize> {
+    pins_pa: [(HostLed, PAx<Output<PushPull>>); A],
+    pins_pb: [(HostLed, PBx<Output<PushPull>>); B],
+    last_state: Option<KeyboardLeds>,
+}
+
+impl<const A: usize, const B: usize> Leds<A, B> {
+    pub fn new(
+        pins_pa: [(HostLed, PAx<Output<PushPull>>); A],
+        pins_pb: [(HostLed, PBx<Output<PushPull>>); B],
+    ) -> Leds<A, B> {
+        Leds {
+            pins_pa,
+            pins_pb,
+            last_state: None,
+        }
+    }
+
+    pub fn update(&mut self, state: KeyboardLeds) {
+        if self.last_state == Some(state) {
+            return;
+        }
+        for (led, pin) in self.pins_pa.iter_mut() {
+            if state.is_on(*led) {
+                pin.set_high().ok();
+            } else {
+                pin.set_low().ok();
+            }
+        }
+        for (led, pin) in self.pins_pb.iter_mut() {
+            if state.is_on(*led) {
+                pin.set_high().ok();
+            } else {
+                pin.set_low().ok();
+            }
+        }
+        self.last_state = Some(state);
+    }
+}
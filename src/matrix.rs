@@ -1,87 +1,231 @@
-use stm32f1xx_hal::gpio::{gpioa::*, gpiob::*, Input, OpenDrain, Output, PullUp};
+use stm32f1xx_hal::gpio::{gpioa::*, gpiob::*, Input, Output};
 //use stm32f1xx_hal::prelude::*;
-use crate::StringSender;
+use crate::key_matrix::{suppress_ghosts, ActiveLevel, KeyMatrix, KeyStates, MatrixConfig};
 use cortex_m;
-use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::digital::v2::OutputPin;
 #[allow(unused_imports)]
 use embedded_hal::digital::v2_compat;
-use no_std_compat::prelude::v1::*;
-use smallbitvec::SmallBitVec;
-
-type SinksA = Vec<PAx<Input<PullUp>>>;
-type SinksB = Vec<PBx<Input<PullUp>>>;
-
-pub struct Matrix {
-    sinks_pa: SinksA,
-    sinks_pb: SinksB,
-    sources_pa: Vec<PAx<Output<OpenDrain>>>,
-    sources_pb: Vec<PBx<Output<OpenDrain>>>,
-    pub output: SmallBitVec,
+use stm32f1xx_hal::stm32;
+
+/// A matrix pin on either port, with its bit in GPIOA IDR | GPIOB IDR << 16.
+/// The downgraded pins don't know their number, build.rs passes it along.
+pub enum PortPin<MODE> {
+    A(PAx<MODE>, u32),
+    B(PBx<MODE>, u32),
+}
+
+impl<MODE> PortPin<MODE> {
+    pub fn pa(pin: PAx<MODE>, number: u8) -> PortPin<MODE> {
+        PortPin::A(pin, 1 << number)
+    }
+
+    pub fn pb(pin: PBx<MODE>, number: u8) -> PortPin<MODE> {
+        PortPin::B(pin, 1 << (number + 16))
+    }
+
+    fn mask(&self) -> u32 {
+        match self {
+            PortPin::A(_, mask) | PortPin::B(_, mask) => *mask,
+        }
+    }
 }
 
-impl Matrix {
+impl<OM> PortPin<Output<OM>>
+where
+    PAx<Output<OM>>: OutputPin,
+    PBx<Output<OM>>: OutputPin,
+{
+    fn set_level(&mut self, high: bool) {
+        match self {
+            PortPin::A(pin, _) => Self::set_pin(pin, high),
+            PortPin::B(pin, _) => Self::set_pin(pin, high),
+        }
+    }
+
+    fn set_pin<P: OutputPin>(pin: &mut P, high: bool) {
+        if high {
+            pin.set_high().ok();
+        } else {
+            pin.set_low().ok();
+        }
+    }
+}
+
+/// GPIO matrix. Outputs are driven one at a time, the inputs read for each.
+///
+/// read_matrix scans all lines, busy waiting for each to settle.
+/// scan_step is the non blocking alternative - it samples the line driven
+/// on the previous call and drives the next one, so the timer interval
+/// between two calls is the settling time.
+///
+/// Which side (sources or sinks) the outputs are depends on the diode
+/// direction and active level in the config, see MatrixConfig::drives_sinks.
+/// IM / OM are the input / output pin modes - PullUp and OpenDrain for
+/// active low matrices, PullDown and PushPull for active high ones.
+///
+/// The input pins are kept to own them, but read through the port's
+/// IDR - one read of GPIOA and GPIOB per line instead of one per pin.
+///
+/// Everything is sized at compile time (build.rs generates the BoardMatrix
+/// type), KEYS has to be INPUTS * OUTPUTS.
+pub struct Matrix<IM, OM, const INPUTS: usize, const OUTPUTS: usize, const KEYS: usize> {
+    inputs: [PortPin<Input<IM>>; INPUTS],
+    outputs: [PortPin<Output<OM>>; OUTPUTS],
+    config: MatrixConfig,
+    /// the last complete scan
+    pub output: KeyStates<KEYS>,
+    /// the scan in progress
+    pending: KeyStates<KEYS>,
+    /// the output line driven by scan_step, sampled on the next call
+    current_line: usize,
+    /// whether the last complete scan differed from the one before
+    changed: bool,
+}
+
+impl<IM, OM, const INPUTS: usize, const OUTPUTS: usize, const KEYS: usize>
+    Matrix<IM, OM, INPUTS, OUTPUTS, KEYS>
+where
+    PAx<Output<OM>>: OutputPin,
+    PBx<Output<OM>>: OutputPin,
+{
     pub fn new(
-        sinks_pa: SinksA,
-        sinks_pb: SinksB,
-        sources_pa: Vec<PAx<Output<OpenDrain>>>,
-        sources_pb: Vec<PBx<Output<OpenDrain>>>,
-    ) -> Matrix {
-        let sink_count = sinks_pa.len() + sinks_pb.len();
-        let source_count = sources_pa.len() + sources_pb.len();
-        let output = SmallBitVec::with_capacity(sink_count * source_count);
-        Matrix {
-            sinks_pa,
-            sinks_pb,
-            sources_pa,
-            sources_pb,
-            output,
+        inputs: [PortPin<Input<IM>>; INPUTS],
+        outputs: [PortPin<Output<OM>>; OUTPUTS],
+        config: MatrixConfig,
+    ) -> Matrix<IM, OM, INPUTS, OUTPUTS, KEYS> {
+        assert!(KEYS == INPUTS * OUTPUTS);
+        let mut matrix = Matrix {
+            inputs,
+            outputs,
+            config,
+            output: KeyStates::new(),
+            pending: KeyStates::new(),
+            current_line: 0,
+            changed: false,
+        };
+        for line in 0..matrix.line_count() {
+            matrix.set_line(line, false);
         }
+        // scan_step samples this on its first call
+        matrix.set_line(0, true);
+        matrix
     }
 
-    pub fn len(&self) -> usize {
-        return self.output.capacity();
+    /// number of output lines, the steps per scan_step scan
+    pub fn line_count(&self) -> usize {
+        OUTPUTS
     }
 
     pub fn read_matrix(&mut self) {
-        self.output.clear();
-        for source in self.sources_pa.iter_mut() {
-            source.set_high().ok();
-        }
-        for source in self.sources_pb.iter_mut() {
-            source.set_high().ok();
+        self.set_line(self.current_line, false);
+        for line in 0..self.line_count() {
+            self.set_line(line, true);
+            cortex_m::asm::delay(4800);
+            self.read_line(line);
+            self.set_line(line, false);
         }
+        self.finish_scan();
+        self.current_line = 0;
+        self.set_line(0, true);
+    }
 
-        for source in self.sources_pa.iter_mut() {
-            source.set_low().ok();
-            Self::read_row(&mut self.output, &self.sinks_pa, &self.sinks_pb);
-            source.set_high().ok();
+    /// Sample the line driven on the last call, then drive the next one.
+    /// Returns true when that completed a scan, which is then in output.
+    pub fn scan_step(&mut self) -> bool {
+        let line = self.current_line;
+        self.read_line(line);
+        self.set_line(line, false);
+        self.current_line = (line + 1) % self.line_count();
+        self.set_line(self.current_line, true);
+        if self.current_line == 0 {
+            self.finish_scan();
+            true
+        } else {
+            false
         }
-        for source in self.sources_pb.iter_mut() {
-            source.set_low().ok();
-            Self::read_row(&mut self.output, &self.sinks_pa, &self.sinks_pb);
-            source.set_high().ok();
+    }
+
+    /// move the pending scan to output, suppressing ghosts if configured
+    fn finish_scan(&mut self) {
+        if self.config.ghost_detection {
+            let (sources, sinks) = (self.source_count(), self.sink_count());
+            suppress_ghosts(&mut self.pending, &self.output, sources, sinks);
         }
+        self.changed = self.pending != self.output;
+        self.output = self.pending;
+    }
+
+    /// whether the last complete scan differed from the one before - a matrix edge
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    fn set_line(&mut self, line: usize, active: bool) {
+        let active_high = self.config.active == ActiveLevel::High;
+        let level = if active { active_high } else { !active_high };
+        self.outputs[line].set_level(level);
+    }
+
+    /// both input ports, GPIOA in the low half, GPIOB in the high half
+    fn read_ports() -> u32 {
+        // reading IDR has no side effects, and the pins read are ours
+        let pa = unsafe { (*stm32::GPIOA::ptr()).idr.read().bits() } & 0xffff;
+        let pb = unsafe { (*stm32::GPIOB::ptr()).idr.read().bits() } & 0xffff;
+        pa | (pb << 16)
     }
 
-    fn read_row(output: &mut SmallBitVec, sinks_pa: &SinksA, sinks_pb: &SinksB) {
-        cortex_m::asm::delay(4800);
-        for sink in sinks_pa.iter() {
-            output.push(sink.is_low().unwrap_or(false));
+    /// read all inputs into pending while output number `line` is active
+    fn read_line(&mut self, line: usize) {
+        let levels = Self::read_ports();
+        // active bits set
+        let active = if self.config.active == ActiveLevel::High {
+            levels
+        } else {
+            !levels
+        };
+        let drives_sinks = self.config.drives_sinks();
+        let sink_count = self.sink_count();
+        // the bit order is source major whichever side is driven
+        for (input, pin) in self.inputs.iter().enumerate() {
+            let (source, sink) = if drives_sinks { (input, line) } else { (line, input) };
+            self.pending.set(source * sink_count + sink, active & pin.mask() != 0);
         }
-        for sink in sinks_pb.iter() {
-            output.push(sink.is_low().unwrap_or(false));
+    }
+}
+
+/// the GPIO backend
+impl<IM, OM, const INPUTS: usize, const OUTPUTS: usize, const KEYS: usize> KeyMatrix<KEYS>
+    for Matrix<IM, OM, INPUTS, OUTPUTS, KEYS>
+where
+    PAx<Output<OM>>: OutputPin,
+    PBx<Output<OM>>: OutputPin,
+{
+    fn scan(&mut self) -> &KeyStates<KEYS> {
+        self.read_matrix();
+        &self.output
+    }
+
+    fn scan_step(&mut self) -> bool {
+        Matrix::scan_step(self)
+    }
+
+    fn output(&self) -> &KeyStates<KEYS> {
+        &self.output
+    }
+
+    fn source_count(&self) -> usize {
+        if self.config.drives_sinks() {
+            INPUTS
+        } else {
+            OUTPUTS
         }
     }
 
-    pub fn debug_serial(states: &SmallBitVec, tx: &mut impl StringSender) {
-        //let mut counter = 0;
-        for (ii, value) in states.iter().enumerate() {
-            if value {
-                let o = format!("{}", ii);
-                tx.writeln(&o);
-                //       counter += 1;
-            }
+    fn sink_count(&self) -> usize {
+        if self.config.drives_sinks() {
+            OUTPUTS
+        } else {
+            INPUTS
         }
-        //tx.writeln(&format!("Count: {}\r\n", counter));
     }
 }
@@ -0,0 +1,388 @@
+use crate::hid::{HidClass, HidDevice, MouseReport, Protocol, ReportProtocol, ReportType, Subclass};
+use crate::report_sink::ReportSink;
+use usb_device::bus::UsbBus;
+
+// Report protocol layout, see hid::MouseReport:
+// 5 buttons + 3 bits padding, X, Y, wheel (generic desktop) and
+// AC Pan (consumer page), all relative and -127..=127.
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01, 0xA1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29, 0x05,
+    0x15, 0x00, 0x25, 0x01, 0x95, 0x05, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x03, 0x81, 0x03,
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x09, 0x38, 0x15, 0x81, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x03,
+    0x81, 0x06, 0x05, 0x0C, 0x0A, 0x38, 0x02, 0x15, 0x81, 0x25, 0x7F, 0x75, 0x08, 0x95, 0x01, 0x81,
+    0x06, 0xC0, 0xC0,
+];
+
+pub struct Mouse {
+    report: MouseReport,
+    protocol: ReportProtocol,
+}
+
+impl Mouse {
+    pub fn new() -> Mouse {
+        Mouse {
+            report: MouseReport::default(),
+            protocol: ReportProtocol::Report,
+        }
+    }
+}
+
+impl HidDevice for Mouse {
+    fn subclass(&self) -> Subclass {
+        Subclass::BootInterface
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::Mouse
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        REPORT_DESCRIPTOR
+    }
+
+    fn get_report(&mut self, report_type: ReportType, _report_id: u8) -> Result<&[u8], ()> {
+        match report_type {
+            ReportType::Input => match self.protocol {
+                ReportProtocol::Boot => Ok(self.report.as_boot_bytes()),
+                ReportProtocol::Report => Ok(self.report.as_bytes()),
+            },
+            _ => Err(()),
+        }
+    }
+
+    fn set_report(
+        &mut self,
+        _report_type: ReportType,
+        _report_id: u8,
+        _data: &[u8],
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn set_protocol(&mut self, protocol: ReportProtocol) {
+        self.protocol = protocol;
+    }
+}
+
+/// the mouse endpoint, reports are written in the encoding matching the host's protocol
+/// and kept for GET_REPORT once the endpoint accepted them
+impl<'a, B: UsbBus> ReportSink<MouseReport> for HidClass<'a, B, Mouse> {
+    fn write_report(&mut self, report: &MouseReport) -> Result<usize, ()> {
+        let written = match self.protocol() {
+            ReportProtocol::Boot => self.write(report.as_boot_bytes()),
+            ReportProtocol::Report => self.write(report.as_bytes()),
+        };
+        if let Ok(count) = written {
+            if count > 0 {
+                self.device_mut().report = *report;
+            }
+        }
+        written
+    }
+}
+
+/// What a mouse key does
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum MouseAction {
+    Up = 0,
+    Down = 1,
+    Left = 2,
+    Right = 3,
+    WheelUp = 4,
+    WheelDown = 5,
+    PanLeft = 6,
+    PanRight = 7,
+    Button1 = 8,
+    Button2 = 9,
+    Button3 = 10,
+    Button4 = 11,
+    Button5 = 12,
+}
+
+impl MouseAction {
+    pub fn new(u: u8) -> Option<MouseAction> {
+        use MouseAction::*;
+        match u {
+            0 => Some(Up),
+            1 => Some(Down),
+            2 => Some(Left),
+            3 => Some(Right),
+            4 => Some(WheelUp),
+            5 => Some(WheelDown),
+            6 => Some(PanLeft),
+            7 => Some(PanRight),
+            8 => Some(Button1),
+            9 => Some(Button2),
+            10 => Some(Button3),
+            11 => Some(Button4),
+            12 => Some(Button5),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << (self as u16)
+    }
+
+    fn button_bit(self) -> Option<u8> {
+        match self {
+            MouseAction::Button1 => Some(0x01),
+            MouseAction::Button2 => Some(0x02),
+            MouseAction::Button3 => Some(0x04),
+            MouseAction::Button4 => Some(0x08),
+            MouseAction::Button5 => Some(0x10),
+            _ => None,
+        }
+    }
+}
+
+/// How the cursor speed ramps up from base_speed to max_speed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccelerationCurve {
+    /// always move at base_speed
+    Constant,
+    /// speed grows linearly with the time the key is held
+    Linear,
+    /// speed grows with the square of the time the key is held -
+    /// precise at first, fast later on
+    Quadratic,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MouseKeysConfig {
+    /// ms between two movement reports
+    pub interval_ms: u16,
+    /// ms after the first movement before the cursor starts to accelerate
+    pub delay_ms: u16,
+    /// ms from the start of acceleration until max_speed is reached
+    pub time_to_max_ms: u16,
+    /// pixels per report
+    pub base_speed: u8,
+    /// pixels per report
+    pub max_speed: u8,
+    /// ms between two wheel / pan steps
+    pub wheel_interval_ms: u16,
+    pub curve: AccelerationCurve,
+}
+
+impl Default for MouseKeysConfig {
+    fn default() -> MouseKeysConfig {
+        MouseKeysConfig {
+            interval_ms: 16,
+            delay_ms: 200,
+            time_to_max_ms: 1500,
+            base_speed: 2,
+            max_speed: 40,
+            wheel_interval_ms: 80,
+            curve: AccelerationCurve::Quadratic,
+        }
+    }
+}
+
+const MOVEMENT_MASK: u16 = 0x0F;
+const WHEEL_MASK: u16 = 0xF0;
+
+/// The mouse keys state machine. Tracks which mouse keys are held
+/// and turns them into reports, advanced by tick() once per millisecond.
+pub struct MouseKeys {
+    config: MouseKeysConfig,
+    held: u16,
+    buttons: u8,
+    held_ms: u16,
+    since_move_ms: u16,
+    since_wheel_ms: u16,
+    dirty: bool,
+    /// movement of reports the host never got (x, y, wheel, pan),
+    /// added to the following reports
+    unsent: [i16; 4],
+}
+
+impl MouseKeys {
+    pub fn new(config: MouseKeysConfig) -> MouseKeys {
+        MouseKeys {
+            config,
+            held: 0,
+            buttons: 0,
+            held_ms: 0,
+            since_move_ms: 0,
+            since_wheel_ms: 0,
+            dirty: false,
+            unsent: [0; 4],
+        }
+    }
+
+    pub fn press(&mut self, action: MouseAction) {
+        match action.button_bit() {
+            Some(bit) => self.buttons |= bit,
+            None => {
+                if self.held & action.bit() == 0 {
+                    // move / scroll right away, the interval starts afterwards
+                    self.since_move_ms = self.config.interval_ms;
+                    self.since_wheel_ms = self.config.wheel_interval_ms;
+                }
+                self.held |= action.bit();
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub fn release(&mut self, action: MouseAction) {
+        match action.button_bit() {
+            Some(bit) => self.buttons &= !bit,
+            None => self.held &= !action.bit(),
+        }
+        self.dirty = true;
+    }
+
+    /// call when the report returned by tick could not be sent -
+    /// its buttons are resent and its movement is added to the next report
+    pub fn report_failed(&mut self, report: &MouseReport) {
+        let deltas = [report.x(), report.y(), report.wheel(), report.pan()];
+        for (unsent, delta) in self.unsent.iter_mut().zip(deltas.iter()) {
+            *unsent = unsent.saturating_add(*delta as i16);
+        }
+        self.dirty = true;
+    }
+
+    /// add the movement not sent yet to a delta,
+    /// keeping what does not fit into the report for later.
+    /// The descriptor's logical range is -127..=127, -128 is out of range.
+    fn catch_up(&mut self, index: usize, delta: i8) -> i8 {
+        let total = self.unsent[index] + delta as i16;
+        let sent = total.max(-i8::MAX as i16).min(i8::MAX as i16);
+        self.unsent[index] = total - sent;
+        sent as i8
+    }
+
+    fn is_held(&self, action: MouseAction) -> bool {
+        self.held & action.bit() != 0
+    }
+
+    fn axis(&self, negative: MouseAction, positive: MouseAction, step: i8) -> i8 {
+        match (self.is_held(negative), self.is_held(positive)) {
+            (true, false) => -step,
+            (false, true) => step,
+            _ => 0,
+        }
+    }
+
+    /// the current cursor speed, depending on how long movement keys were held
+    fn speed(&self) -> i8 {
+        let base = self.config.base_speed.min(127) as u32;
+        let max = (self.config.max_speed.min(127) as u32).max(base);
+        if self.held_ms <= self.config.delay_ms {
+            return base as i8;
+        }
+        let time_to_max = self.config.time_to_max_ms.max(1) as u32;
+        let t = ((self.held_ms - self.config.delay_ms) as u32).min(time_to_max);
+        // fraction of max speed reached, 0..=256
+        let fraction = t * 256 / time_to_max;
+        let fraction = match self.config.curve {
+            AccelerationCurve::Constant => 0,
+            AccelerationCurve::Linear => fraction,
+            AccelerationCurve::Quadratic => fraction * fraction / 256,
+        };
+        (base + (max - base) * fraction / 256) as i8
+    }
+
+    /// Advance by one millisecond.
+    /// Returns the report to send, if any.
+    pub fn tick(&mut self) -> Option<MouseReport> {
+        let mut x = 0;
+        let mut y = 0;
+        let mut wheel = 0;
+        let mut pan = 0;
+
+        if self.held & MOVEMENT_MASK != 0 {
+            self.held_ms = self.held_ms.saturating_add(1);
+            self.since_move_ms = self.since_move_ms.saturating_add(1);
+            if self.since_move_ms >= self.config.interval_ms {
+                let speed = self.speed();
+                x = self.axis(MouseAction::Left, MouseAction::Right, speed);
+                y = self.axis(MouseAction::Up, MouseAction::Down, speed);
+                self.since_move_ms = 0;
+            }
+        } else {
+            self.held_ms = 0;
+        }
+
+        if self.held & WHEEL_MASK != 0 {
+            self.since_wheel_ms = self.since_wheel_ms.saturating_add(1);
+            if self.since_wheel_ms >= self.config.wheel_interval_ms {
+                wheel = self.axis(MouseAction::WheelDown, MouseAction::WheelUp, 1);
+                pan = self.axis(MouseAction::PanLeft, MouseAction::PanRight, 1);
+                self.since_wheel_ms = 0;
+            }
+        }
+
+        let x = self.catch_up(0, x);
+        let y = self.catch_up(1, y);
+        let wheel = self.catch_up(2, wheel);
+        let pan = self.catch_up(3, pan);
+
+        if x != 0 || y != 0 || wheel != 0 || pan != 0 || self.dirty {
+            self.dirty = false;
+            Some(MouseReport::new(self.buttons, x, y, wheel, pan))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MouseKeysConfig {
+        MouseKeysConfig {
+            interval_ms: 10,
+            curve: AccelerationCurve::Constant,
+            base_speed: 5,
+            ..MouseKeysConfig::default()
+        }
+    }
+
+    #[test]
+    fn failed_movement_is_sent_later() {
+        let mut mouse = MouseKeys::new(config());
+        mouse.press(MouseAction::Right);
+        let report = mouse.tick().unwrap();
+        assert_eq!(report.x(), 5);
+        mouse.report_failed(&report);
+        // the retry carries the lost movement without waiting for the interval
+        let retry = mouse.tick().unwrap();
+        assert_eq!((retry.x(), retry.y()), (5, 0));
+        assert!(mouse.tick().is_none());
+    }
+
+    #[test]
+    fn failed_retry_is_retried_again() {
+        let mut mouse = MouseKeys::new(config());
+        mouse.press(MouseAction::Up);
+        mouse.press(MouseAction::WheelDown);
+        let report = mouse.tick().unwrap();
+        assert_eq!((report.y(), report.wheel()), (-5, -1));
+        mouse.report_failed(&report);
+        let retry = mouse.tick().unwrap();
+        mouse.report_failed(&retry);
+        let report = mouse.tick().unwrap();
+        assert_eq!((report.y(), report.wheel()), (-5, -1));
+        assert!(mouse.tick().is_none());
+    }
+
+    #[test]
+    fn movement_beyond_a_report_is_split() {
+        let mut mouse = MouseKeys::new(config());
+        mouse.press(MouseAction::Left);
+        let mut report = mouse.tick().unwrap();
+        for _ in 0..30 {
+            mouse.report_failed(&report);
+            report = MouseReport::new(0, -5, 0, 0, 0);
+        }
+        mouse.release(MouseAction::Left);
+        assert_eq!(mouse.tick().unwrap().x(), -127);
+        assert_eq!(mouse.tick().unwrap().x(), -23);
+        assert!(mouse.tick().is_none());
+    }
+}
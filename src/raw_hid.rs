@@ -0,0 +1,246 @@
+use crate::debounce::Debounce;
+use crate::hid::{HidClass, HidDevice, Protocol, ReportType, Subclass};
+use crate::key_matrix::KeyStates;
+use crate::keymap_store::FlashError;
+use crate::trallocator;
+use keytokey::KeyboardState;
+use no_std_compat::prelude::v1::*;
+use usb_device::bus::UsbBus;
+
+/// Raw HID reports are always 64 bytes, in both directions
+pub const RAW_REPORT_LEN: usize = 64;
+
+// Vendor defined usage page 0xFF60, usage 0x61 (same as QMK, so existing
+// host tools find the interface), one 64 byte input and one 64 byte output report.
+const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, 0x09, 0x61, 0xA1, 0x01, 0x09, 0x62, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95, 0x40,
+    0x75, 0x08, 0x81, 0x02, 0x09, 0x63, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95, 0x40, 0x75, 0x08, 0x91,
+    0x02, 0xC0,
+];
+
+/// The requests a host tool can send.
+///
+/// A request is an output report with the command in byte 0.
+/// The response is an input report echoing the command in byte 0,
+/// a RawStatus in byte 1 and the payload from byte 2 on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RawCommand {
+    /// payload: length, then the firmware version string
+    Version = 0x01,
+    /// payload: heap bytes in use, peak and size, then the allocation that
+    /// ran out of memory before the last reset (0 = none), u32 little endian each
+    HeapUsage = 0x02,
+    /// payload: number of keys, u16 little endian, then one bit per key (pressed)
+    MatrixState = 0x03,
+    /// request byte 1: number of handlers to query.
+    /// payload: one bit per handler ID (enabled)
+    HandlerState = 0x04,
+    /// request bytes 1..3: matrix index, u16 little endian.
+    /// payload: keycode u32 little endian, keymap length u16 little endian
+    KeymapGet = 0x05,
+    /// request bytes 1..3: matrix index, u16 little endian,
+    /// bytes 3..7: keycode, u32 little endian. Applies immediately,
+    /// refused with KeyPressed while the key is held.
+    KeymapSet = 0x06,
+    /// write the current keymap to flash.
+    /// build_response leaves the save to the caller, see save_status
+    KeymapSave = 0x07,
+    /// go back to the compiled in keymap (until saved, only until the next reset)
+    KeymapDefault = 0x08,
+}
+
+impl RawCommand {
+    fn new(u: u8) -> Option<RawCommand> {
+        use RawCommand::*;
+        match u {
+            0x01 => Some(Version),
+            0x02 => Some(HeapUsage),
+            0x03 => Some(MatrixState),
+            0x04 => Some(HandlerState),
+            0x05 => Some(KeymapGet),
+            0x06 => Some(KeymapSet),
+            0x07 => Some(KeymapSave),
+            0x08 => Some(KeymapDefault),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum RawStatus {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    InvalidIndex = 0x02,
+    FlashError = 0x03,
+    KeyPressed = 0x04,
+}
+
+const PAYLOAD_START: usize = 2;
+const PAYLOAD_LEN: usize = RAW_REPORT_LEN - PAYLOAD_START;
+
+/// Vendor defined HID interface for host tools.
+/// Requests arrive via SET_REPORT and are picked up with take_request,
+/// responses are kept until the endpoint accepts them.
+pub struct RawHid {
+    request: Option<[u8; RAW_REPORT_LEN]>,
+    response: Option<[u8; RAW_REPORT_LEN]>,
+}
+
+impl RawHid {
+    pub fn new() -> RawHid {
+        RawHid {
+            request: None,
+            response: None,
+        }
+    }
+
+    /// the last request received from the host, if not yet answered.
+    /// None while the previous response is still waiting for the endpoint.
+    pub fn take_request(&mut self) -> Option<[u8; RAW_REPORT_LEN]> {
+        if self.response.is_some() {
+            return None;
+        }
+        self.request.take()
+    }
+}
+
+impl<'a, B: UsbBus> HidClass<'a, B, RawHid> {
+    /// queue a response and try to write it right away
+    pub fn respond(&mut self, response: [u8; RAW_REPORT_LEN]) {
+        self.device_mut().response = Some(response);
+        self.flush_response();
+    }
+
+    /// retry the response the endpoint did not accept yet
+    pub fn flush_response(&mut self) {
+        if let Some(response) = self.device().response {
+            if let Ok(0) = self.write(&response) {
+                return;
+            }
+            self.device_mut().response = None;
+        }
+    }
+}
+
+impl HidDevice for RawHid {
+    fn subclass(&self) -> Subclass {
+        Subclass::None
+    }
+
+    fn protocol(&self) -> Protocol {
+        Protocol::None
+    }
+
+    fn report_descriptor(&self) -> &[u8] {
+        REPORT_DESCRIPTOR
+    }
+
+    fn get_report(&mut self, _report_type: ReportType, _report_id: u8) -> Result<&[u8], ()> {
+        Err(())
+    }
+
+    fn set_report(
+        &mut self,
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
+    ) -> Result<(), ()> {
+        if report_type == ReportType::Output && report_id == 0 && !data.is_empty() {
+            let mut request = [0u8; RAW_REPORT_LEN];
+            let len = data.len().min(RAW_REPORT_LEN);
+            request[..len].copy_from_slice(&data[..len]);
+            self.request = Some(request);
+            return Ok(());
+        }
+        Err(())
+    }
+
+    fn max_packet_size(&self) -> u16 {
+        RAW_REPORT_LEN as u16
+    }
+}
+
+fn pack_bits(payload: &mut [u8], bits: impl Iterator<Item = bool>) {
+    for (ii, bit) in bits.take(payload.len() * 8).enumerate() {
+        if bit {
+            payload[ii / 8] |= 1 << (ii % 8);
+        }
+    }
+}
+
+/// the status of a KeymapSave response, once the caller has saved
+pub fn save_status(result: Result<(), FlashError>) -> RawStatus {
+    match result {
+        Ok(()) => RawStatus::Ok,
+        Err(_) => RawStatus::FlashError,
+    }
+}
+
+/// answer a raw HID request
+pub fn build_response<D: Debounce, const N: usize>(
+    request: &[u8; RAW_REPORT_LEN],
+    heap: &trallocator::HeapUsage,
+    matrix: &KeyStates<N>,
+    debouncer: &D,
+    state: &KeyboardState,
+    keymap: &mut [u32],
+    default_keymap: &[u32],
+) -> [u8; RAW_REPORT_LEN] {
+    let mut response = [0u8; RAW_REPORT_LEN];
+    response[0] = request[0];
+    response[1] = RawStatus::Ok as u8;
+    let payload = &mut response[PAYLOAD_START..];
+    match RawCommand::new(request[0]) {
+        Some(RawCommand::Version) => {
+            let version = env!("CARGO_PKG_VERSION").as_bytes();
+            let len = version.len().min(PAYLOAD_LEN - 1);
+            payload[0] = len as u8;
+            payload[1..=len].copy_from_slice(&version[..len]);
+        }
+        Some(RawCommand::HeapUsage) => {
+            let values = [heap.used, heap.peak, heap.size, heap.last_oom];
+            for (chunk, value) in payload.chunks_mut(4).zip(values.iter()) {
+                chunk.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        Some(RawCommand::MatrixState) => {
+            payload[..2].copy_from_slice(&(matrix.len() as u16).to_le_bytes());
+            pack_bits(&mut payload[2..], matrix.iter());
+        }
+        Some(RawCommand::HandlerState) => {
+            let count = request[1] as usize;
+            pack_bits(payload, (0..count).map(|id| state.is_handler_enabled(id)));
+        }
+        Some(RawCommand::KeymapGet) => {
+            let index = u16::from_le_bytes([request[1], request[2]]) as usize;
+            match keymap.get(index) {
+                Some(keycode) => {
+                    payload[..4].copy_from_slice(&keycode.to_le_bytes());
+                    payload[4..6].copy_from_slice(&(keymap.len() as u16).to_le_bytes());
+                }
+                None => response[1] = RawStatus::InvalidIndex as u8,
+            }
+        }
+        Some(RawCommand::KeymapSet) => {
+            let index = u16::from_le_bytes([request[1], request[2]]) as usize;
+            let keycode = u32::from_le_bytes([request[3], request[4], request[5], request[6]]);
+            match keymap.get_mut(index) {
+                // see console's set
+                Some(_) if debouncer.is_pressed(index) => {
+                    response[1] = RawStatus::KeyPressed as u8
+                }
+                Some(entry) => *entry = keycode,
+                None => response[1] = RawStatus::InvalidIndex as u8,
+            }
+        }
+        // saved by the caller, outside of the K2K lock
+        Some(RawCommand::KeymapSave) => {}
+        Some(RawCommand::KeymapDefault) => {
+            keymap.copy_from_slice(default_keymap);
+        }
+        None => response[1] = RawStatus::UnknownCommand as u8,
+    }
+    response
+}
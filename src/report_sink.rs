@@ -0,0 +1,237 @@
+use crate::hid::KbHidReport;
+use crate::keyboard::KeyboardLeds;
+use crate::ring_buffer::Queue;
+use no_std_compat::prelude::v1::*;
+
+/// reports queued while the keyboard endpoint is busy, more replace the newest
+pub const REPORT_QUEUE_SIZE: usize = 16;
+
+/// Where reports of type R end up - a HID endpoint on target.
+pub trait ReportSink<R = KbHidReport> {
+    /// write one report, Ok(0) means the endpoint is busy and it has to be retried
+    fn write_report(&mut self, report: &R) -> Result<usize, ()>;
+}
+
+/// The keyboard endpoint, which also hears back from the host.
+pub trait KeyboardSink: ReportSink {
+    /// LED state as last sent by the host
+    fn host_leds(&self) -> KeyboardLeds;
+
+    /// Advance the idle timer by one millisecond,
+    /// true once the last report should be repeated.
+    fn tick_idle(&mut self) -> bool;
+}
+
+/// The report diffing and buffering of USBOut:
+/// only changed reports are written, reports the sink did not
+/// accept are queued (up to N) and retried by flush().
+pub struct ReportQueue<S: ReportSink, const N: usize> {
+    pub sink: S,
+    last_report: KbHidReport,
+    buffer: Queue<KbHidReport, N>,
+}
+
+impl<S: ReportSink, const N: usize> ReportQueue<S, N> {
+    pub fn new(sink: S) -> ReportQueue<S, N> {
+        ReportQueue {
+            sink,
+            last_report: KbHidReport::default(),
+            buffer: Queue::new(),
+        }
+    }
+
+    /// returns whether a report was written to the sink.
+    /// While reports are queued, new ones queue up behind them,
+    /// so the host never sees them out of order.
+    pub fn send(&mut self, report: KbHidReport) -> bool {
+        if report == self.last_report {
+            return false;
+        }
+        self.last_report = report;
+        if !self.buffer.is_empty() {
+            self.buffer.push_latest(report);
+            return false;
+        }
+        match self.sink.write_report(&report) {
+            Ok(0) => {
+                self.buffer.push_latest(report);
+            }
+            Ok(_i) => return true, //we wrote the complete report, presumably.
+            Err(_) => {}
+        };
+        false
+    }
+
+    /// retry the oldest report that the sink did not accept,
+    /// returns whether it was written
+    pub fn flush(&mut self) -> bool {
+        if let Some(report) = self.buffer.front() {
+            match self.sink.write_report(&report) {
+                Ok(0) => {} // still busy, stays queued
+                Ok(_i) => {
+                    self.buffer.pop_front();
+                    return true; //complete report, presumably
+                }
+                Err(_) => {
+                    self.buffer.pop_front();
+                }
+            };
+        }
+        false
+    }
+
+    /// write the last report again (idle repeat), unless reports are still queued
+    pub fn repeat_last(&mut self) {
+        if self.buffer.is_empty() {
+            let report = self.last_report;
+            self.sink.write_report(&report).ok();
+        }
+    }
+
+    pub fn last_report(&self) -> &KbHidReport {
+        &self.last_report
+    }
+}
+
+/// Sink that keeps every report with the time it was written, for tests and the simulator.
+pub struct RecordingSink<R = KbHidReport> {
+    /// the time stamp for the next reports, kept up to date by the caller
+    pub time_ms: u32,
+    /// (time ms, report), oldest first
+    pub reports: Vec<(u32, R)>,
+    /// while set, writes are refused like by a busy endpoint
+    pub busy: bool,
+}
+
+impl<R> RecordingSink<R> {
+    pub fn new() -> RecordingSink<R> {
+        RecordingSink {
+            time_ms: 0,
+            reports: Vec::new(),
+            busy: false,
+        }
+    }
+
+    /// take the reports recorded so far
+    pub fn take(&mut self) -> Vec<(u32, R)> {
+        core::mem::take(&mut self.reports)
+    }
+}
+
+impl<R: Copy> ReportSink<R> for RecordingSink<R> {
+    fn write_report(&mut self, report: &R) -> Result<usize, ()> {
+        if self.busy {
+            return Ok(0);
+        }
+        self.reports.push((self.time_ms, *report));
+        Ok(core::mem::size_of::<R>())
+    }
+}
+
+/// no host - the LEDs stay off and reports are never repeated
+impl KeyboardSink for RecordingSink {
+    fn host_leds(&self) -> KeyboardLeds {
+        KeyboardLeds::default()
+    }
+
+    fn tick_idle(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keytokey::KeyCode;
+
+    fn report(keys: &[KeyCode]) -> KbHidReport {
+        let mut report = KbHidReport::default();
+        for key in keys {
+            report.pressed(*key);
+        }
+        report
+    }
+
+    fn queue<const N: usize>() -> ReportQueue<RecordingSink, N> {
+        ReportQueue::new(RecordingSink::new())
+    }
+
+    fn written<const N: usize>(queue: &mut ReportQueue<RecordingSink, N>) -> Vec<KbHidReport> {
+        queue.sink.take().into_iter().map(|(_time, report)| report).collect()
+    }
+
+    #[test]
+    fn only_changes_are_written() {
+        let mut queue = queue::<4>();
+        assert!(!queue.send(KbHidReport::default()));
+        assert!(queue.send(report(&[KeyCode::A])));
+        assert!(!queue.send(report(&[KeyCode::A])));
+        assert!(queue.send(KbHidReport::default()));
+        assert!(written(&mut queue) == [report(&[KeyCode::A]), KbHidReport::default()]);
+    }
+
+    #[test]
+    fn busy_sink_queues_in_order() {
+        let mut queue = queue::<4>();
+        queue.sink.busy = true;
+        assert!(!queue.send(report(&[KeyCode::A])));
+        queue.sink.busy = false;
+        // the release must not overtake the queued press
+        assert!(!queue.send(KbHidReport::default()));
+        assert!(written(&mut queue).is_empty());
+        assert!(queue.flush());
+        assert!(queue.flush());
+        assert!(!queue.flush());
+        assert!(written(&mut queue) == [report(&[KeyCode::A]), KbHidReport::default()]);
+    }
+
+    #[test]
+    fn flush_keeps_reports_while_busy() {
+        let mut queue = queue::<4>();
+        queue.sink.busy = true;
+        queue.send(report(&[KeyCode::A]));
+        queue.send(report(&[KeyCode::A, KeyCode::B]));
+        assert!(!queue.flush());
+        queue.sink.busy = false;
+        queue.send(report(&[KeyCode::B]));
+        while queue.flush() {}
+        assert!(
+            written(&mut queue)
+                == [
+                    report(&[KeyCode::A]),
+                    report(&[KeyCode::A, KeyCode::B]),
+                    report(&[KeyCode::B]),
+                ]
+        );
+    }
+
+    #[test]
+    fn full_queue_replaces_the_newest() {
+        let mut queue = queue::<2>();
+        queue.sink.busy = true;
+        queue.send(report(&[KeyCode::A]));
+        queue.send(report(&[KeyCode::B]));
+        queue.send(report(&[KeyCode::C]));
+        queue.sink.busy = false;
+        while queue.flush() {}
+        assert!(written(&mut queue) == [report(&[KeyCode::A]), report(&[KeyCode::C])]);
+        assert!(*queue.last_report() == report(&[KeyCode::C]));
+    }
+
+    #[test]
+    fn no_repeat_while_queued() {
+        let mut queue = queue::<4>();
+        queue.send(report(&[KeyCode::A]));
+        queue.repeat_last();
+        assert!(written(&mut queue) == [report(&[KeyCode::A]), report(&[KeyCode::A])]);
+
+        queue.sink.busy = true;
+        queue.send(KbHidReport::default());
+        queue.sink.busy = false;
+        queue.repeat_last();
+        assert!(written(&mut queue).is_empty());
+        queue.flush();
+        queue.repeat_last();
+        assert!(written(&mut queue) == [KbHidReport::default(), KbHidReport::default()]);
+    }
+}
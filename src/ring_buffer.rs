@@ -0,0 +1,112 @@
+pub const RING_BUFFER_SIZE: usize = 256;
+
+/// A fixed size byte queue that never blocks the writer -
+/// bytes that don't fit are dropped.
+pub struct RingBuffer {
+    data: [u8; RING_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            data: [0; RING_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// append as many bytes as fit, returns the number stored
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(RING_BUFFER_SIZE - self.len);
+        for b in &bytes[..count] {
+            self.data[(self.start + self.len) % RING_BUFFER_SIZE] = *b;
+            self.len += 1;
+        }
+        count
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.data[self.start];
+        self.consume(1);
+        Some(b)
+    }
+
+    /// the oldest buffered bytes that are stored contiguously
+    pub fn peek(&self) -> &[u8] {
+        let end = (self.start + self.len).min(RING_BUFFER_SIZE);
+        &self.data[self.start..end]
+    }
+
+    /// drop the oldest count bytes, i.e. after writing out peek()
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.start = (self.start + count) % RING_BUFFER_SIZE;
+        self.len -= count;
+    }
+}
+
+/// A fixed size FIFO of N items, for queueing reports
+/// without the heap.
+pub struct Queue<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    start: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub fn new() -> Queue<T, N> {
+        Queue {
+            items: [None; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append an item. When full the newest item is replaced instead,
+    /// reports are complete states, so the latest one matters most.
+    pub fn push_latest(&mut self, item: T) {
+        if N == 0 {
+            return;
+        }
+        if self.len == N {
+            self.items[(self.start + N - 1) % N] = Some(item);
+        } else {
+            self.items[(self.start + self.len) % N] = Some(item);
+            self.len += 1;
+        }
+    }
+
+    /// the oldest item
+    pub fn front(&self) -> Option<T> {
+        if self.len == 0 {
+            None
+        } else {
+            self.items[self.start]
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = self.front()?;
+        self.items[self.start] = None;
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(item)
+    }
+}
@@ -1,11 +1,34 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem::MaybeUninit;
+use core::ptr;
 use core::sync::atomic::{AtomicU32, Ordering};
 
-pub struct Trallocator<A: GlobalAlloc>(pub A, AtomicU32);
+const OOM_MAGIC: u32 = 0x00D1_E0F0;
+
+/// the failed allocation's size, survives the reset since .uninit is not zeroed
+#[link_section = ".uninit.OOM_RECORD"]
+static mut OOM_RECORD: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+/// size of the allocation that ran out of memory before the last reset, 0 = none
+static LAST_OOM: AtomicU32 = AtomicU32::new(0);
+
+/// Heap statistics, in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct HeapUsage {
+    pub used: u32,
+    /// the most ever in use since boot
+    pub peak: u32,
+    pub size: u32,
+    /// the allocation that failed before the last reset, 0 = none
+    pub last_oom: u32,
+}
+
+pub struct Trallocator<A: GlobalAlloc>(pub A, AtomicU32, AtomicU32);
 
 unsafe impl<A: GlobalAlloc> GlobalAlloc for Trallocator<A> {
     unsafe fn alloc(&self, l: Layout) -> *mut u8 {
-        self.1.fetch_add(l.size() as u32, Ordering::SeqCst);
+        let used = self.1.fetch_add(l.size() as u32, Ordering::SeqCst) + l.size() as u32;
+        self.2.fetch_max(used, Ordering::SeqCst);
         self.0.alloc(l)
     }
     unsafe fn dealloc(&self, ptr: *mut u8, l: Layout) {
@@ -16,7 +39,7 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for Trallocator<A> {
 
 impl<A: GlobalAlloc> Trallocator<A> {
     pub const fn new(a: A) -> Self {
-        Trallocator(a, AtomicU32::new(0))
+        Trallocator(a, AtomicU32::new(0), AtomicU32::new(0))
     }
 
     /*pub fn reset(&self) {
@@ -26,4 +49,35 @@ impl<A: GlobalAlloc> Trallocator<A> {
     pub fn get(&self) -> u32 {
         self.1.load(Ordering::SeqCst)
     }
-}
\ No newline at end of file
+
+    pub fn usage(&self, size: u32) -> HeapUsage {
+        HeapUsage {
+            used: self.get(),
+            peak: self.2.load(Ordering::SeqCst),
+            size,
+            last_oom: LAST_OOM.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// For the alloc error handler - remember the failed allocation
+/// for after the reset that follows.
+pub fn record_oom(size: usize) {
+    unsafe {
+        ptr::write_volatile(OOM_RECORD.as_mut_ptr(), [OOM_MAGIC, size as u32]);
+    }
+}
+
+/// To be called once in init - picks up the record of an
+/// out of memory reset, returns the failed allocation's size.
+pub fn check_oom_record() -> Option<u32> {
+    let [magic, size] = unsafe { ptr::read_volatile(OOM_RECORD.as_ptr()) };
+    if magic != OOM_MAGIC {
+        return None;
+    }
+    unsafe {
+        ptr::write_volatile(OOM_RECORD.as_mut_ptr(), [0, 0]);
+    }
+    LAST_OOM.store(size, Ordering::SeqCst);
+    Some(size)
+}
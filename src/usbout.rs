@@ -1,59 +1,117 @@
-use crate::hid::KbHidReport;
-use crate::KeyboardHidClass;
-use core::clone::Clone;
+use crate::extended_handlers::ExtendedKeyOut;
+use crate::hid::{ConsumerReport, ExtraReport, KbHidReport, MouseReport, SystemReport};
+use crate::keyboard::KeyboardLeds;
+use crate::latency::Latency;
+use crate::mouse::{MouseAction, MouseKeys, MouseKeysConfig};
+use crate::report_sink::{KeyboardSink, ReportQueue, ReportSink, REPORT_QUEUE_SIZE};
+use crate::ring_buffer::Queue;
 use keytokey::{KeyCode, KeyboardState, USBKeyOut};
-use no_std_compat::collections::VecDeque;
-use no_std_compat::prelude::v1::*;
 
-use stm32f1;
-use stm32f1xx_hal::serial;
+pub trait StringSender {
+    fn writeln(&mut self, s: &str);
+}
 
-pub struct USBOut {
+/// Turns keytokey's output into HID reports.
+///
+/// Generic over where the reports go, so the simulator runs the same
+/// report building on RecordingSinks as the firmware on its HID endpoints:
+/// K is the keyboard endpoint, E the extra keys (consumer / system control)
+/// endpoint, M the mouse endpoint and L receives the debug output.
+pub struct USBOut<K: KeyboardSink, E, M, L> {
     state: KeyboardState,
-    pub usb_class: KeyboardHidClass,
+    /// the keyboard endpoint, behind the report diffing and buffering
+    pub keyboard: ReportQueue<K, REPORT_QUEUE_SIZE>,
+    pub extra: E,
+    pub mouse: M,
+    /// debug output, and on target the other interfaces for host tools
+    pub link: L,
+    mouse_keys: MouseKeys,
     current_report: KbHidReport,
-    last_report: KbHidReport,
-    pub tx: serial::Tx<stm32f1::stm32f103::USART1>,
-    pub buffer: VecDeque<KbHidReport>,
+    /// TIM4 time, kept up to date by the TIM4 interrupt
+    pub time_ms: u32,
+    /// matrix edge to keyboard report latency
+    pub latency: Latency,
+    extra_buffer: Queue<ExtraReport, REPORT_QUEUE_SIZE>,
 }
 
-unsafe impl Sync for USBOut {}
-
-impl USBOut {
-    pub fn new(usb_class: KeyboardHidClass, tx: serial::Tx<stm32f1::stm32f103::USART1>) -> USBOut {
+impl<K, E, M, L> USBOut<K, E, M, L>
+where
+    K: KeyboardSink,
+    E: ReportSink<ExtraReport>,
+    M: ReportSink<MouseReport>,
+    L: StringSender,
+{
+    pub fn new(keyboard: K, extra: E, mouse: M, link: L) -> USBOut<K, E, M, L> {
         USBOut {
             state: KeyboardState::new(),
-            usb_class,
+            keyboard: ReportQueue::new(keyboard),
+            extra,
+            mouse,
+            link,
+            mouse_keys: MouseKeys::new(MouseKeysConfig::default()),
             current_report: KbHidReport::default(),
-            last_report: KbHidReport::default(),
-            tx,
-            buffer: VecDeque::new(),
+            time_ms: 0,
+            latency: Latency::new(),
+            extra_buffer: Queue::new(),
         }
     }
 
     fn send_report(&mut self, report: KbHidReport) {
-        /*
-         */
-        if report != self.last_report {
-            /*
-            use crate::StringSender;
-            if report.as_bytes() != [0u8; 8] {
-                self.tx.writeln(&format!("{:?}", report.as_bytes()));
+        if self.keyboard.send(report) {
+            self.latency.written(self.time_ms);
+        }
+    }
+
+    /// called every millisecond - repeats the last report
+    /// once the host's idle period has elapsed
+    pub fn tick_idle(&mut self) {
+        if self.keyboard.sink.tick_idle() {
+            self.keyboard.repeat_last();
+        }
+    }
+
+    /// called every millisecond - moves the cursor while mouse keys are held
+    pub fn tick_mouse(&mut self) {
+        if let Some(report) = self.mouse_keys.tick() {
+            if let Ok(0) = self.mouse.write_report(&report) {
+                self.mouse_keys.report_failed(&report);
             }
-            */
-            self.last_report = report;
-            match self.usb_class.write(report.as_bytes()) {
-                Ok(0) => {
-                    self.buffer.push_back(report);
-                }
-                Ok(_i) => {} //we wrote the complete report, presumably.
-                Err(_) => {}
-            };
+        }
+    }
+
+    /// send a report on the extra keys interface,
+    /// queueing it behind any report still waiting for the endpoint
+    fn send_extra_report(&mut self, report: ExtraReport) {
+        if !self.extra_buffer.is_empty() {
+            self.extra_buffer.push_latest(report);
+            return;
+        }
+        if let Ok(0) = self.extra.write_report(&report) {
+            self.extra_buffer.push_latest(report);
+        }
+    }
+
+    /// retry the oldest report that the endpoint did not accept
+    pub fn flush_buffer(&mut self) {
+        if self.keyboard.flush() {
+            self.latency.written(self.time_ms);
+        }
+        if let Some(report) = self.extra_buffer.front() {
+            if let Ok(0) = self.extra.write_report(&report) {
+                return;
+            }
+            self.extra_buffer.pop_front();
         }
     }
 }
 
-impl USBKeyOut for USBOut {
+impl<K, E, M, L> USBKeyOut for USBOut<K, E, M, L>
+where
+    K: KeyboardSink,
+    E: ReportSink<ExtraReport>,
+    M: ReportSink<MouseReport>,
+    L: StringSender,
+{
     /// send these USB Keycodes concurrently rigth away.
     fn send_keys(&mut self, keys: &[KeyCode]) {
         let mut report = KbHidReport::default();
@@ -68,7 +126,7 @@ impl USBKeyOut for USBOut {
     }
     /// send registered keycodes (or an empty nothing-pressed status)
     fn send_registered(&mut self) {
-        let report = self.current_report.clone();
+        let report = self.current_report;
         self.send_report(report);
         self.current_report.clear();
     }
@@ -80,15 +138,127 @@ impl USBKeyOut for USBOut {
 
     /// retrieve a mutable KeyboardState
     fn state(&mut self) -> &mut KeyboardState {
-        return &mut self.state;
+        &mut self.state
     }
 
     /// retrieve a KeyboardState
     fn ro_state(&self) -> &KeyboardState {
-        return &self.state;
+        &self.state
     }
     fn debug(&mut self, s: &str){
-        use crate::StringSender;
-        self.tx.writeln(s);
+        self.link.writeln(s);
+    }
+}
+
+impl<K, E, M, L> ExtendedKeyOut for USBOut<K, E, M, L>
+where
+    K: KeyboardSink,
+    E: ReportSink<ExtraReport>,
+    M: ReportSink<MouseReport>,
+    L: StringSender,
+{
+    fn host_leds(&self) -> KeyboardLeds {
+        self.keyboard.sink.host_leds()
+    }
+
+    fn send_consumer(&mut self, usage: u16) {
+        self.send_extra_report(ExtraReport::Consumer(ConsumerReport::new(usage)));
+    }
+
+    fn send_system(&mut self, usage: u8) {
+        self.send_extra_report(ExtraReport::System(SystemReport::new(usage)));
+    }
+
+    fn mouse_action(&mut self, action: MouseAction, pressed: bool) {
+        if pressed {
+            self.mouse_keys.press(action);
+        } else {
+            self.mouse_keys.release(action);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid::SystemUsage;
+    use crate::report_sink::RecordingSink;
+    use no_std_compat::prelude::v1::*;
+
+    struct Lines(Vec<String>);
+
+    impl StringSender for Lines {
+        fn writeln(&mut self, s: &str) {
+            self.0.push(s.to_string());
+        }
+    }
+
+    type TestOut = USBOut<
+        RecordingSink,
+        RecordingSink<ExtraReport>,
+        RecordingSink<MouseReport>,
+        Lines,
+    >;
+
+    fn output() -> TestOut {
+        USBOut::new(
+            RecordingSink::new(),
+            RecordingSink::new(),
+            RecordingSink::new(),
+            Lines(Vec::new()),
+        )
+    }
+
+    fn extra_bytes(output: &mut TestOut) -> Vec<Vec<u8>> {
+        output.extra.take().iter().map(|(_, r)| r.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn keyboard_reports() {
+        let mut output = output();
+        output.register_key(KeyCode::A);
+        output.send_registered();
+        output.send_empty();
+        output.debug("hello");
+        let reports = output.keyboard.sink.take();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].1.as_bytes()[2], KeyCode::A.to_u8());
+        assert!(reports[1].1 == KbHidReport::default());
+        assert_eq!(output.link.0, ["hello"]);
+    }
+
+    #[test]
+    fn consumer_and_system_reports() {
+        let mut output = output();
+        output.send_consumer(0xE9);
+        output.send_system(SystemUsage::Sleep as u8);
+        assert_eq!(extra_bytes(&mut output), [vec![1, 0xE9, 0], vec![2, 0x82]]);
+
+        // queued in order while the endpoint is busy
+        output.extra.busy = true;
+        output.send_consumer(0);
+        output.send_system(0);
+        output.flush_buffer();
+        assert!(output.extra.reports.is_empty());
+        output.extra.busy = false;
+        output.flush_buffer();
+        output.flush_buffer();
+        assert_eq!(extra_bytes(&mut output), [vec![1, 0, 0], vec![2, 0]]);
+    }
+
+    #[test]
+    fn mouse_reports() {
+        let mut output = output();
+        output.mouse_action(MouseAction::Button1, true);
+        output.tick_mouse();
+        output.mouse_action(MouseAction::Button1, false);
+        output.mouse.busy = true;
+        output.tick_mouse();
+        output.mouse.busy = false;
+        output.tick_mouse();
+        output.tick_mouse();
+        let reports: Vec<Vec<u8>> =
+            output.mouse.take().iter().map(|(_, r)| r.as_bytes().to_vec()).collect();
+        assert_eq!(reports, [vec![1, 0, 0, 0, 0], vec![0, 0, 0, 0, 0]]);
     }
 }